@@ -17,6 +17,22 @@ pub enum BuilderError {
     #[error("Platform not available: {0}")]
     PlatformNotAvailable(String),
 
+    #[error("Digest mismatch: expected {expected}, got {actual}")]
+    DigestMismatch { expected: String, actual: String },
+
+    #[error("Size mismatch for {digest}: expected {expected} bytes, got {actual}")]
+    SizeMismatch {
+        digest: String,
+        expected: u64,
+        actual: u64,
+    },
+
+    #[error("Registry rate limit reached, retry in {retry_after_secs}s")]
+    RateLimited { retry_after_secs: u64 },
+
+    #[error("Unsupported layer type: {0}")]
+    UnsupportedLayer(String),
+
     #[error("Layer extraction failed: {0}")]
     LayerExtraction(String),
 
@@ -0,0 +1,717 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use tar::{Archive, EntryType};
+use tracing::debug;
+
+use super::{ImageSource, LayerCompression, LayerReader};
+use crate::registry::{verify_blob, ImageManifest, LayerDescriptor, PullOptions, VerifyingReader};
+
+/// Media type reported for layers stored in a `docker save` archive
+const LAYER_MEDIA_TYPE: &str = "application/vnd.docker.image.rootfs.diff.tar";
+
+#[derive(Debug, Deserialize)]
+struct ManifestEntry {
+    #[serde(rename = "Config")]
+    config: String,
+    #[serde(default, rename = "RepoTags")]
+    repo_tags: Option<Vec<String>>,
+    #[serde(rename = "Layers")]
+    layers: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageConfig {
+    rootfs: RootFs,
+}
+
+#[derive(Debug, Deserialize)]
+struct RootFs {
+    diff_ids: Vec<String>,
+}
+
+/// Legacy `repositories` file: repository -> tag -> top layer id
+type Repositories = HashMap<String, HashMap<String, String>>;
+
+/// An image tarball produced by `docker save`
+pub struct DockerArchive {
+    path: PathBuf,
+    manifest: ImageManifest,
+    config: Vec<u8>,
+    /// (data offset, size) of each layer inside the archive
+    layer_spans: Vec<(u64, u64)>,
+}
+
+impl DockerArchive {
+    /// Open an archive and select the image tagged `tag` (or the only image when `None`)
+    pub fn open(path: impl Into<PathBuf>, tag: Option<&str>) -> Result<Self> {
+        let path = path.into();
+
+        let files = read_files(&path, |name| {
+            name == "manifest.json" || name == "repositories"
+        })?;
+        let entries: Vec<ManifestEntry> = serde_json::from_slice(
+            files
+                .get("manifest.json")
+                .with_context(|| format!("{:?} has no manifest.json", path))?,
+        )
+        .with_context(|| format!("Invalid manifest.json in {:?}", path))?;
+        let repositories: Repositories = match files.get("repositories") {
+            Some(data) => serde_json::from_slice(data)
+                .with_context(|| format!("Invalid repositories file in {:?}", path))?,
+            None => Repositories::new(),
+        };
+
+        let entry = select_entry(&entries, &repositories, tag)
+            .with_context(|| format!("Failed to select image in {:?}", path))?;
+        debug!("Selected image with config {}", entry.config);
+
+        let raw_config = read_files(&path, |name| name == entry.config)?
+            .remove(&entry.config)
+            .with_context(|| format!("Config {} missing from {:?}", entry.config, path))?;
+        let config_digest = match config_digest(&entry.config) {
+            Some(digest) => {
+                verify_blob(&digest, &raw_config, None)
+                    .with_context(|| format!("Config {} failed verification", entry.config))?;
+                digest
+            }
+            None => {
+                debug!("Config {} is not named after its digest", entry.config);
+                format!("sha256:{:x}", Sha256::digest(&raw_config))
+            }
+        };
+        let config: ImageConfig = serde_json::from_slice(&raw_config)
+            .with_context(|| format!("Invalid image config {}", entry.config))?;
+
+        if config.rootfs.diff_ids.len() != entry.layers.len() {
+            anyhow::bail!(
+                "Image config lists {} layers but manifest.json has {}",
+                config.rootfs.diff_ids.len(),
+                entry.layers.len()
+            );
+        }
+
+        let contents = archive_entries(&path)?;
+        let layer_spans = entry
+            .layers
+            .iter()
+            .map(|layer| {
+                data_span(&contents, layer)
+                    .with_context(|| format!("Layer {} missing from {:?}", layer, path))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let layers: Vec<LayerDescriptor> = config
+            .rootfs
+            .diff_ids
+            .iter()
+            .zip(&layer_spans)
+            .map(|(diff_id, (_, size))| LayerDescriptor {
+                digest: diff_id.clone(),
+                size: *size,
+                media_type: LAYER_MEDIA_TYPE.to_string(),
+            })
+            .collect();
+        let total_size = layers.iter().map(|l| l.size).sum();
+
+        Ok(Self {
+            path,
+            manifest: ImageManifest {
+                config_digest,
+                config_size: raw_config.len() as u64,
+                layers,
+                total_size,
+            },
+            config: raw_config,
+            layer_spans,
+        })
+    }
+
+    /// Parse a `docker-archive:` image argument (`./image.tar` or `./image.tar:repo:tag`)
+    ///
+    /// Paths may contain `:` themselves, so the split is made where an existing
+    /// file ends, or at the first `:` of the file name when there is none.
+    pub fn parse_reference(spec: &str) -> (PathBuf, Option<String>) {
+        if Path::new(spec).is_file() {
+            return (PathBuf::from(spec), None);
+        }
+        let mut splits = spec.match_indices(':').map(|(index, _)| index);
+        let file_name_start = spec.rfind('/').map_or(0, |slash| slash + 1);
+        let split = splits
+            .clone()
+            .find(|&index| Path::new(&spec[..index]).is_file())
+            .or_else(|| splits.find(|&index| index > file_name_start));
+        match split {
+            Some(index) if index > 0 && index + 1 < spec.len() => (
+                PathBuf::from(&spec[..index]),
+                Some(spec[index + 1..].to_string()),
+            ),
+            _ => (PathBuf::from(spec), None),
+        }
+    }
+
+    /// Manifest of the selected image
+    pub fn manifest(&self) -> &ImageManifest {
+        &self.manifest
+    }
+}
+
+#[async_trait]
+impl ImageSource for DockerArchive {
+    async fn resolve_manifest(&self, _options: &PullOptions) -> Result<ImageManifest> {
+        Ok(self.manifest.clone())
+    }
+
+    async fn open_layer(
+        &self,
+        layer: &LayerDescriptor,
+        _options: &PullOptions,
+    ) -> Result<LayerReader> {
+        let position = self
+            .manifest
+            .layers
+            .iter()
+            .position(|l| l.digest == layer.digest)
+            .with_context(|| format!("Layer {} is not part of this image", layer.digest))?;
+        let (offset, size) = self.layer_spans[position];
+
+        // Read the layer in place. It may be stored compressed (the containerd
+        // image store keeps the registry blobs), while its diff_id is the digest
+        // of the uncompressed tar, so decompress here and check what comes out.
+        let mut file =
+            File::open(&self.path).with_context(|| format!("Failed to open {:?}", self.path))?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut stored = BufReader::new(file.take(size));
+        let tar: LayerReader = match LayerCompression::detect(stored.fill_buf()?) {
+            LayerCompression::Gzip => Box::new(GzDecoder::new(stored)),
+            LayerCompression::Zstd => Box::new(
+                zstd::stream::read::Decoder::with_buffer(stored)
+                    .context("Failed to initialize zstd decoder")?,
+            ),
+            _ => Box::new(stored),
+        };
+        Ok(Box::new(VerifyingReader::new(tar, &layer.digest, None)?))
+    }
+
+    async fn fetch_config(
+        &self,
+        _manifest: &ImageManifest,
+        _options: &PullOptions,
+    ) -> Result<Vec<u8>> {
+        Ok(self.config.clone())
+    }
+}
+
+/// Pick the manifest entry for `tag`, falling back to the legacy `repositories` file
+fn select_entry<'a>(
+    entries: &'a [ManifestEntry],
+    repositories: &Repositories,
+    tag: Option<&str>,
+) -> Result<&'a ManifestEntry> {
+    let tag = match tag {
+        Some(tag) => tag,
+        None => match entries {
+            [single] => return Ok(single),
+            [] => anyhow::bail!("Archive contains no images"),
+            _ => anyhow::bail!(
+                "Archive contains {} images, specify one with docker-archive:<path>:<repo>:<tag>",
+                entries.len()
+            ),
+        },
+    };
+
+    if let Some(entry) = entries.iter().find(|e| {
+        e.repo_tags
+            .iter()
+            .flatten()
+            .any(|t| t == tag || t.strip_prefix("docker.io/library/") == Some(tag))
+    }) {
+        return Ok(entry);
+    }
+
+    // Legacy archives map repo:tag to the id of the image's top layer
+    let (repo, tag_name) = tag.rsplit_once(':').unwrap_or((tag, "latest"));
+    let layer_id = repositories
+        .get(repo)
+        .and_then(|tags| tags.get(tag_name))
+        .with_context(|| format!("Image {} not found in archive", tag))?;
+
+    entries
+        .iter()
+        .find(|e| {
+            e.layers
+                .last()
+                .is_some_and(|l| l.trim_end_matches("/layer.tar") == layer_id)
+        })
+        .with_context(|| format!("Image {} not found in archive", tag))
+}
+
+/// Read the files matching `wanted` from a tar archive into memory
+fn read_files(path: &Path, wanted: impl Fn(&str) -> bool) -> Result<HashMap<String, Vec<u8>>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let mut archive = Archive::new(file);
+    let mut files = HashMap::new();
+
+    for entry in archive.entries_with_seek()? {
+        let mut entry = entry?;
+        let name = normalize_name(&entry.path()?.to_string_lossy());
+        if wanted(&name) {
+            let mut data = Vec::with_capacity(entry.size() as usize);
+            entry.read_to_end(&mut data)?;
+            files.insert(name, data);
+        }
+    }
+
+    Ok(files)
+}
+
+/// A file of the archive: where its data is stored, or the file it links to
+enum ArchiveEntry {
+    Data { offset: u64, size: u64 },
+    Link(String),
+}
+
+/// Every file and link in the archive, keyed by normalized name
+fn archive_entries(path: &Path) -> Result<HashMap<String, ArchiveEntry>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let mut archive = Archive::new(file);
+    let mut entries = HashMap::new();
+
+    for entry in archive.entries_with_seek()? {
+        let entry = entry?;
+        let name = normalize_name(&entry.path()?.to_string_lossy());
+        let target = || -> Result<String> {
+            let target = entry
+                .link_name()?
+                .with_context(|| format!("Link {} has no target", name))?;
+            Ok(target.to_string_lossy().into_owned())
+        };
+
+        let archive_entry = match entry.header().entry_type() {
+            // Symlink targets are relative to the link's directory
+            EntryType::Symlink => ArchiveEntry::Link(join_link(&name, &target()?)),
+            EntryType::Link => ArchiveEntry::Link(join_link("", &target()?)),
+            _ => ArchiveEntry::Data {
+                offset: entry.raw_file_position(),
+                size: entry.size(),
+            },
+        };
+        entries.insert(name, archive_entry);
+    }
+
+    Ok(entries)
+}
+
+/// Data offset and size of the file `name`, following links
+///
+/// `docker save` stores layers shared between images once and links the others to them.
+fn data_span(entries: &HashMap<String, ArchiveEntry>, name: &str) -> Result<(u64, u64)> {
+    let mut name = name;
+    // Same limit as the kernel's symlink resolution
+    for _ in 0..40 {
+        match entries.get(name) {
+            Some(ArchiveEntry::Data { offset, size }) => return Ok((*offset, *size)),
+            Some(ArchiveEntry::Link(target)) => name = target,
+            None => anyhow::bail!("{} is not in the archive", name),
+        }
+    }
+    anyhow::bail!("Too many levels of links at {}", name)
+}
+
+/// Archive path a link at `name` points to with `target`
+fn join_link(name: &str, target: &str) -> String {
+    let dir = if target.starts_with('/') {
+        ""
+    } else {
+        name.rsplit_once('/').map_or("", |(dir, _)| dir)
+    };
+
+    let mut parts = Vec::new();
+    for part in dir.split('/').chain(target.split('/')) {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim_start_matches("./").to_string()
+}
+
+/// Config digest from its file name (`<hex>.json`, `sha256:<hex>` or `blobs/sha256/<hex>`)
+fn config_digest(config_path: &str) -> Option<String> {
+    let name = config_path.rsplit('/').next().unwrap_or(config_path);
+    let name = name.strip_suffix(".json").unwrap_or(name);
+    let hex = name.strip_prefix("sha256:").unwrap_or(name);
+    (hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+        .then(|| format!("sha256:{}", hex))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    fn sha256(data: &[u8]) -> String {
+        format!("sha256:{:x}", Sha256::digest(data))
+    }
+
+    /// Image config of an image made of `layers`
+    fn config(layers: &[&[u8]]) -> Vec<u8> {
+        let diff_ids: Vec<String> = layers.iter().map(|l| sha256(l)).collect();
+        serde_json::json!({"rootfs": {"type": "layers", "diff_ids": diff_ids}})
+            .to_string()
+            .into_bytes()
+    }
+
+    /// File name `docker save` gives a config
+    fn config_name(config: &[u8]) -> String {
+        format!("{}.json", sha256(config).trim_start_matches("sha256:"))
+    }
+
+    fn append(builder: &mut tar::Builder<File>, name: &str, data: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, data).unwrap();
+    }
+
+    fn create_archive(dir: &TempDir) -> PathBuf {
+        let path = dir.path().join("image.tar");
+        let mut builder = tar::Builder::new(File::create(&path).unwrap());
+
+        let c1 = config(&[b"first layer"]);
+        let c2 = config(&[b"first layer", b"second"]);
+        let manifest = format!(
+            r#"[
+                {{"Config": "{}", "RepoTags": ["app:v1"], "Layers": ["l1/layer.tar"]}},
+                {{"Config": "{}", "RepoTags": ["app:v2"], "Layers": ["l1/layer.tar", "l2/layer.tar"]}}
+            ]"#,
+            config_name(&c1),
+            config_name(&c2)
+        );
+        append(&mut builder, "manifest.json", manifest.as_bytes());
+        append(
+            &mut builder,
+            "repositories",
+            br#"{"legacy": {"latest": "l2"}}"#,
+        );
+        append(&mut builder, &config_name(&c1), &c1);
+        append(&mut builder, &config_name(&c2), &c2);
+        append(&mut builder, "l1/layer.tar", b"first layer");
+        append(&mut builder, "l2/layer.tar", b"second");
+        builder.finish().unwrap();
+
+        path
+    }
+
+    #[test]
+    fn test_parse_reference() {
+        assert_eq!(
+            DockerArchive::parse_reference("./image.tar"),
+            (PathBuf::from("./image.tar"), None)
+        );
+        assert_eq!(
+            DockerArchive::parse_reference("./image.tar:app:v1"),
+            (PathBuf::from("./image.tar"), Some("app:v1".to_string()))
+        );
+        // A ':' in a directory name is not a tag separator
+        assert_eq!(
+            DockerArchive::parse_reference("./build:2024/image.tar:app:v1"),
+            (
+                PathBuf::from("./build:2024/image.tar"),
+                Some("app:v1".to_string())
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_reference_existing_path_with_colon() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().join("build:2024");
+        std::fs::create_dir(&dir).unwrap();
+        let archive = dir.join("image:latest.tar");
+        std::fs::write(&archive, b"").unwrap();
+        let spec = archive.to_str().unwrap();
+
+        assert_eq!(
+            DockerArchive::parse_reference(spec),
+            (archive.clone(), None)
+        );
+        // Repository names may contain '/' and ':', the file decides the split
+        assert_eq!(
+            DockerArchive::parse_reference(&format!("{}:localhost:5000/app:v1", spec)),
+            (archive, Some("localhost:5000/app:v1".to_string()))
+        );
+    }
+
+    /// Every layer of the selected image, read through [`ImageSource::open_layer`]
+    async fn read_layers(archive: &DockerArchive) -> Vec<Vec<u8>> {
+        let options = PullOptions::default();
+        let mut layers = Vec::new();
+        for layer in &archive.manifest().layers {
+            let mut data = Vec::new();
+            archive
+                .open_layer(layer, &options)
+                .await
+                .unwrap()
+                .read_to_end(&mut data)
+                .unwrap();
+            layers.push(data);
+        }
+        layers
+    }
+
+    #[tokio::test]
+    async fn test_open_by_tag() {
+        let dir = TempDir::new().unwrap();
+        let path = create_archive(&dir);
+
+        let archive = DockerArchive::open(&path, Some("app:v2")).unwrap();
+        let manifest = archive.manifest();
+        assert_eq!(
+            manifest.config_digest,
+            sha256(&config(&[b"first layer", b"second"]))
+        );
+        assert_eq!(manifest.layers.len(), 2);
+        assert_eq!(manifest.layers[1].digest, sha256(b"second"));
+        assert_eq!(manifest.layers[1].size, 6);
+        assert_eq!(manifest.total_size, 17);
+
+        assert_eq!(
+            read_layers(&archive).await,
+            vec![b"first layer".to_vec(), b"second".to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_open_layer_reads_in_place() {
+        let dir = TempDir::new().unwrap();
+        let path = create_archive(&dir);
+
+        let archive = DockerArchive::open(&path, Some("app:v2")).unwrap();
+        let options = PullOptions::default();
+        let manifest = archive.resolve_manifest(&options).await.unwrap();
+
+        let mut data = Vec::new();
+        archive
+            .open_layer(&manifest.layers[1], &options)
+            .await
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, b"second");
+    }
+
+    #[tokio::test]
+    async fn test_linked_layers() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("image.tar");
+        let mut builder = tar::Builder::new(File::create(&path).unwrap());
+
+        let c = config(&[b"shared".as_slice(); 3]);
+        let manifest = format!(
+            r#"[{{"Config": "{}", "Layers": ["l1/layer.tar", "l2/layer.tar", "l3/layer.tar"]}}]"#,
+            config_name(&c)
+        );
+        append(&mut builder, "manifest.json", manifest.as_bytes());
+        append(&mut builder, &config_name(&c), &c);
+        append(&mut builder, "l1/layer.tar", b"shared");
+        // How `docker save` stores a layer already written for another image
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "l2/layer.tar", "../l1/layer.tar")
+            .unwrap();
+        header.set_entry_type(tar::EntryType::Link);
+        builder
+            .append_link(&mut header, "l3/layer.tar", "l2/layer.tar")
+            .unwrap();
+        builder.finish().unwrap();
+
+        let archive = DockerArchive::open(&path, None).unwrap();
+        assert_eq!(archive.manifest().total_size, 18);
+        assert_eq!(read_layers(&archive).await, vec![b"shared".to_vec(); 3]);
+    }
+
+    #[test]
+    fn test_dangling_layer_link() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("image.tar");
+        let mut builder = tar::Builder::new(File::create(&path).unwrap());
+
+        let c = config(&[b"missing"]);
+        let manifest = format!(
+            r#"[{{"Config": "{}", "Layers": ["l1/layer.tar"]}}]"#,
+            config_name(&c)
+        );
+        append(&mut builder, "manifest.json", manifest.as_bytes());
+        append(&mut builder, &config_name(&c), &c);
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "l1/layer.tar", "../l0/layer.tar")
+            .unwrap();
+        builder.finish().unwrap();
+
+        let err = DockerArchive::open(&path, None).err().unwrap();
+        assert!(format!("{:#}", err).contains("l0/layer.tar is not in the archive"));
+    }
+
+    #[tokio::test]
+    async fn test_gzipped_layer() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("image.tar");
+        let mut builder = tar::Builder::new(File::create(&path).unwrap());
+
+        // The containerd image store saves the compressed registry blobs
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"compressed layer").unwrap();
+        let blob = encoder.finish().unwrap();
+        let c = config(&[b"compressed layer"]);
+        let blob_name = format!(
+            "blobs/sha256/{}",
+            sha256(&blob).trim_start_matches("sha256:")
+        );
+        let manifest = format!(
+            r#"[{{"Config": "{}", "Layers": ["{}"]}}]"#,
+            config_name(&c),
+            blob_name
+        );
+        append(&mut builder, "manifest.json", manifest.as_bytes());
+        append(&mut builder, &config_name(&c), &c);
+        append(&mut builder, &blob_name, &blob);
+        builder.finish().unwrap();
+
+        let archive = DockerArchive::open(&path, None).unwrap();
+        assert_eq!(archive.manifest().layers[0].size, blob.len() as u64);
+        assert_eq!(
+            read_layers(&archive).await,
+            vec![b"compressed layer".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_config_digest() {
+        let hex = "a".repeat(64);
+        let digest = Some(format!("sha256:{}", hex));
+        assert_eq!(config_digest(&format!("{}.json", hex)), digest);
+        assert_eq!(config_digest(&format!("blobs/sha256/{}", hex)), digest);
+        // crane/ggcr tarballs
+        assert_eq!(config_digest(&format!("sha256:{}", hex)), digest);
+        assert_eq!(config_digest("config.json"), None);
+    }
+
+    #[tokio::test]
+    async fn test_config_named_without_digest() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("image.tar");
+        let mut builder = tar::Builder::new(File::create(&path).unwrap());
+
+        let c = config(&[b"layer"]);
+        append(
+            &mut builder,
+            "manifest.json",
+            br#"[{"Config": "config.json", "Layers": ["l1/layer.tar"]}]"#,
+        );
+        append(&mut builder, "config.json", &c);
+        append(&mut builder, "l1/layer.tar", b"layer");
+        builder.finish().unwrap();
+
+        let archive = DockerArchive::open(&path, None).unwrap();
+        assert_eq!(archive.manifest().config_digest, sha256(&c));
+        assert_eq!(read_layers(&archive).await, vec![b"layer".to_vec()]);
+    }
+
+    #[test]
+    fn test_join_link() {
+        assert_eq!(join_link("l2/layer.tar", "../l1/layer.tar"), "l1/layer.tar");
+        assert_eq!(join_link("l2/layer.tar", "./layer.1"), "l2/layer.1");
+        assert_eq!(join_link("l2/layer.tar", "/l1/layer.tar"), "l1/layer.tar");
+        assert_eq!(join_link("", "./l1/layer.tar"), "l1/layer.tar");
+    }
+
+    #[test]
+    fn test_open_requires_tag_with_several_images() {
+        let dir = TempDir::new().unwrap();
+        let path = create_archive(&dir);
+
+        assert!(DockerArchive::open(&path, None).is_err());
+        assert!(DockerArchive::open(&path, Some("app:v3")).is_err());
+    }
+
+    #[test]
+    fn test_open_legacy_repositories() {
+        let dir = TempDir::new().unwrap();
+        let path = create_archive(&dir);
+
+        let archive = DockerArchive::open(&path, Some("legacy")).unwrap();
+        assert_eq!(
+            archive.manifest().config_digest,
+            sha256(&config(&[b"first layer", b"second"]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_layer_fails() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("image.tar");
+        let mut builder = tar::Builder::new(File::create(&path).unwrap());
+
+        let c = config(&[b"original"]);
+        let manifest = format!(
+            r#"[{{"Config": "{}", "Layers": ["l1/layer.tar"]}}]"#,
+            config_name(&c)
+        );
+        append(&mut builder, "manifest.json", manifest.as_bytes());
+        append(&mut builder, &config_name(&c), &c);
+        append(&mut builder, "l1/layer.tar", b"tampered");
+        builder.finish().unwrap();
+
+        let archive = DockerArchive::open(&path, None).unwrap();
+        let layer = &archive.manifest().layers[0];
+        let mut data = Vec::new();
+        let err = archive
+            .open_layer(layer, &PullOptions::default())
+            .await
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_corrupt_config_fails() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("image.tar");
+        let mut builder = tar::Builder::new(File::create(&path).unwrap());
+
+        let c = config(&[b"layer"]);
+        let manifest = format!(
+            r#"[{{"Config": "{}", "Layers": ["l1/layer.tar"]}}]"#,
+            config_name(&c)
+        );
+        append(&mut builder, "manifest.json", manifest.as_bytes());
+        append(&mut builder, &config_name(&c), &config(&[b"other"]));
+        append(&mut builder, "l1/layer.tar", b"layer");
+        builder.finish().unwrap();
+
+        let err = DockerArchive::open(&path, None).err().unwrap();
+        assert!(format!("{:#}", err).contains("failed verification"));
+    }
+}
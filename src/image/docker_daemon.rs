@@ -0,0 +1,337 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::sync::OnceCell;
+use tracing::{debug, info};
+
+use super::{DockerArchive, ImageSource, LayerReader};
+use crate::registry::{ImageManifest, LayerDescriptor, PullOptions};
+
+const DOCKER_SOCKET: &str = "/var/run/docker.sock";
+const PODMAN_SOCKET: &str = "/run/podman/podman.sock";
+
+/// Client for the image export endpoint of a Docker (or Podman) daemon
+pub struct DockerDaemon {
+    socket: PathBuf,
+}
+
+impl DockerDaemon {
+    /// Talk to the daemon listening on `socket`
+    pub fn new(socket: impl Into<PathBuf>) -> Self {
+        Self {
+            socket: socket.into(),
+        }
+    }
+
+    /// Locate the daemon socket from `DOCKER_HOST` or the usual Docker/Podman paths
+    pub fn from_env() -> Result<Self> {
+        if let Ok(host) = std::env::var("DOCKER_HOST") {
+            let socket = host
+                .strip_prefix("unix://")
+                .with_context(|| format!("Unsupported DOCKER_HOST {}, expected unix://", host))?;
+            return Ok(Self::new(socket));
+        }
+
+        let mut candidates = vec![PathBuf::from(DOCKER_SOCKET)];
+        if let Some(runtime_dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+            candidates.push(PathBuf::from(runtime_dir).join("podman/podman.sock"));
+        }
+        candidates.push(PathBuf::from(PODMAN_SOCKET));
+
+        candidates
+            .into_iter()
+            .find(|path| path.exists())
+            .map(Self::new)
+            .context("No Docker or Podman socket found, set DOCKER_HOST=unix:///path/to/socket")
+    }
+
+    /// Export `image` as a `docker save` tarball written to `dest`
+    ///
+    /// Returns the number of bytes written.
+    pub async fn export_image(&self, image: &str, dest: &Path) -> Result<u64> {
+        info!("Exporting {} from daemon at {:?}", image, self.socket);
+
+        let stream = UnixStream::connect(&self.socket)
+            .await
+            .with_context(|| format!("Failed to connect to {:?}", self.socket))?;
+        let (read_half, mut write_half) = stream.into_split();
+
+        let request = format!(
+            "GET /images/{}/get HTTP/1.1\r\nHost: docker\r\nConnection: close\r\n\r\n",
+            encode_path(image)
+        );
+        write_half.write_all(request.as_bytes()).await?;
+
+        let mut reader = BufReader::new(read_half);
+        let response = read_response_head(&mut reader).await?;
+        debug!("Daemon responded with status {}", response.status);
+
+        if response.status != 200 {
+            let mut body = Vec::new();
+            copy_body(&mut reader, &response, &mut body).await?;
+            anyhow::bail!(
+                "Daemon failed to export {} (HTTP {}): {}",
+                image,
+                response.status,
+                String::from_utf8_lossy(&body).trim()
+            );
+        }
+
+        let mut file = File::create(dest)
+            .await
+            .with_context(|| format!("Failed to create {:?}", dest))?;
+        let written = copy_body(&mut reader, &response, &mut file).await?;
+        file.flush().await?;
+
+        Ok(written)
+    }
+}
+
+/// An image exported from the daemon on first use, then read as a [`DockerArchive`]
+pub struct DaemonImage {
+    daemon: DockerDaemon,
+    name: String,
+    exported: OnceCell<(TempDir, DockerArchive)>,
+}
+
+impl DaemonImage {
+    pub fn new(daemon: DockerDaemon, name: &str) -> Self {
+        Self {
+            daemon,
+            name: name.to_string(),
+            exported: OnceCell::new(),
+        }
+    }
+
+    async fn archive(&self) -> Result<&DockerArchive> {
+        let (_, archive) = self
+            .exported
+            .get_or_try_init(|| async {
+                let export_dir = TempDir::new()?;
+                let archive_path = export_dir.path().join("image.tar");
+
+                let size = self.daemon.export_image(&self.name, &archive_path).await?;
+                info!("Exported {} bytes from daemon", size);
+
+                // The export only contains the requested image
+                let archive = DockerArchive::open(&archive_path, None)?;
+                Ok::<_, anyhow::Error>((export_dir, archive))
+            })
+            .await?;
+        Ok(archive)
+    }
+}
+
+#[async_trait]
+impl ImageSource for DaemonImage {
+    async fn resolve_manifest(&self, options: &PullOptions) -> Result<ImageManifest> {
+        self.archive().await?.resolve_manifest(options).await
+    }
+
+    async fn open_layer(
+        &self,
+        layer: &LayerDescriptor,
+        options: &PullOptions,
+    ) -> Result<LayerReader> {
+        self.archive().await?.open_layer(layer, options).await
+    }
+
+    async fn fetch_config(
+        &self,
+        manifest: &ImageManifest,
+        options: &PullOptions,
+    ) -> Result<Vec<u8>> {
+        self.archive().await?.fetch_config(manifest, options).await
+    }
+}
+
+struct ResponseHead {
+    status: u16,
+    content_length: Option<u64>,
+    chunked: bool,
+}
+
+async fn read_response_head<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> Result<ResponseHead> {
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .with_context(|| format!("Invalid HTTP status line: {:?}", line.trim()))?;
+
+    let mut head = ResponseHead {
+        status,
+        content_length: None,
+        chunked: false,
+    };
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            anyhow::bail!("Connection closed while reading response headers");
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                head.content_length = value.parse().ok();
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                head.chunked = value.eq_ignore_ascii_case("chunked");
+            }
+        }
+    }
+
+    Ok(head)
+}
+
+/// Copy the response body to `out`, decoding chunked transfer encoding
+async fn copy_body<R, W>(reader: &mut R, head: &ResponseHead, out: &mut W) -> Result<u64>
+where
+    R: AsyncBufReadExt + AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    if !head.chunked {
+        return match head.content_length {
+            Some(length) => Ok(tokio::io::copy(&mut reader.take(length), out).await?),
+            None => Ok(tokio::io::copy(reader, out).await?),
+        };
+    }
+
+    let mut total = 0;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line).await?;
+        let size_field = line.trim().split(';').next().unwrap_or("");
+        let size = u64::from_str_radix(size_field, 16)
+            .with_context(|| format!("Invalid chunk size {:?}", line.trim()))?;
+
+        if size == 0 {
+            // Skip optional trailers up to the final empty line
+            loop {
+                line.clear();
+                if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+                    break;
+                }
+            }
+            return Ok(total);
+        }
+
+        let copied = tokio::io::copy(&mut (&mut *reader).take(size), out).await?;
+        if copied != size {
+            anyhow::bail!("Connection closed in the middle of a chunk");
+        }
+        total += copied;
+
+        line.clear();
+        reader.read_line(&mut line).await?;
+    }
+}
+
+/// Percent-encode characters that are not valid in a URL path segment
+fn encode_path(image: &str) -> String {
+    image
+        .bytes()
+        .map(|b| match b {
+            b'a'..=b'z'
+            | b'A'..=b'Z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'.'
+            | b'_'
+            | b'~'
+            | b'/'
+            | b':'
+            | b'@' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use tokio::net::UnixListener;
+
+    /// Serve a single canned HTTP response on a Unix socket, returning the request line
+    async fn serve_once(listener: UnixListener, response: Vec<u8>) -> String {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (read_half, mut write_half) = stream.into_split();
+        let mut reader = BufReader::new(read_half);
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).await.unwrap();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+            if line.trim().is_empty() {
+                break;
+            }
+        }
+
+        write_half.write_all(&response).await.unwrap();
+        write_half.shutdown().await.unwrap();
+        request_line
+    }
+
+    #[tokio::test]
+    async fn test_export_chunked_response() {
+        let dir = TempDir::new().unwrap();
+        let socket = dir.path().join("docker.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+
+        let response = b"HTTP/1.1 200 OK\r\nContent-Type: application/x-tar\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n".to_vec();
+        let server = tokio::spawn(serve_once(listener, response));
+
+        let dest = dir.path().join("image.tar");
+        let written = DockerDaemon::new(&socket)
+            .export_image("myapp:dev", &dest)
+            .await
+            .unwrap();
+
+        assert_eq!(written, 11);
+        assert_eq!(std::fs::read(&dest).unwrap(), b"hello world");
+        assert_eq!(
+            server.await.unwrap().trim(),
+            "GET /images/myapp:dev/get HTTP/1.1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export_error_response() {
+        let dir = TempDir::new().unwrap();
+        let socket = dir.path().join("docker.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+
+        let body = r#"{"message":"reference does not exist"}"#;
+        let response = format!(
+            "HTTP/1.1 404 Not Found\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let server = tokio::spawn(serve_once(listener, response.into_bytes()));
+
+        let err = DockerDaemon::new(&socket)
+            .export_image("missing", &dir.path().join("image.tar"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("reference does not exist"));
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn test_encode_path() {
+        assert_eq!(encode_path("ghcr.io/user/app:v1"), "ghcr.io/user/app:v1");
+        assert_eq!(encode_path("app tag"), "app%20tag");
+    }
+}
@@ -2,22 +2,87 @@ use anyhow::{Context, Result};
 use flate2::read::GzDecoder;
 use std::collections::HashSet;
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::io::{self, BufRead, BufReader, Cursor, Read};
+use std::path::{Component, Path, PathBuf};
 use tar::Archive;
-use tracing::debug;
+use tracing::{debug, warn};
+
+use super::resolve::resolve_in_root;
+use crate::error::BuilderError;
+use crate::initramfs::{FileMetadata, MetadataOverlay, SpecialFile, Xattr};
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// How a layer blob is compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerCompression {
+    None,
+    Gzip,
+    Zstd,
+    /// Not known from the media type, detected from the first bytes
+    Detect,
+}
+
+impl LayerCompression {
+    /// Compression of a layer with `media_type`
+    ///
+    /// Fails for foreign/non-distributable layers (Windows base layers) and for
+    /// media types that are not filesystem layers at all.
+    pub fn from_media_type(media_type: &str) -> Result<Self, BuilderError> {
+        match media_type {
+            "application/vnd.oci.image.layer.v1.tar" => Ok(Self::None),
+            "application/vnd.oci.image.layer.v1.tar+gzip"
+            | "application/vnd.docker.image.rootfs.diff.tar.gzip" => Ok(Self::Gzip),
+            "application/vnd.oci.image.layer.v1.tar+zstd"
+            | "application/vnd.docker.image.rootfs.diff.tar.zstd" => Ok(Self::Zstd),
+            // `docker save` archives use the plain type whatever the compression
+            "" | "application/vnd.docker.image.rootfs.diff.tar" => Ok(Self::Detect),
+            foreign if foreign.contains(".foreign.") || foreign.contains(".nondistributable.") => {
+                Err(BuilderError::UnsupportedLayer(format!(
+                    "{} (foreign layers are not distributable and cannot be extracted)",
+                    foreign
+                )))
+            }
+            other => Err(BuilderError::UnsupportedLayer(other.to_string())),
+        }
+    }
+
+    /// Compression of a blob starting with `magic`, `None` when it is a plain tar
+    pub(crate) fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(&GZIP_MAGIC) {
+            Self::Gzip
+        } else if magic.starts_with(&ZSTD_MAGIC) {
+            Self::Zstd
+        } else {
+            Self::None
+        }
+    }
+}
+
+/// A layer entry skipped because it would have reached outside the rootfs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectedEntry {
+    pub path: PathBuf,
+    pub reason: String,
+}
 
 pub struct LayerExtractor {
     exclude_patterns: Vec<glob::Pattern>,
-    whiteouts: HashSet<PathBuf>,
-    opaque_dirs: HashSet<PathBuf>,
+    rejected: Vec<RejectedEntry>,
+    metadata: MetadataOverlay,
 }
 
 impl LayerExtractor {
     pub fn new() -> Self {
         Self {
             exclude_patterns: Vec::new(),
-            whiteouts: HashSet::new(),
-            opaque_dirs: HashSet::new(),
+            rejected: Vec::new(),
+            metadata: MetadataOverlay::new(),
         }
     }
 
@@ -30,6 +95,25 @@ impl LayerExtractor {
         Ok(self)
     }
 
+    /// Entries skipped so far because they tried to escape the rootfs
+    pub fn rejected_entries(&self) -> &[RejectedEntry] {
+        &self.rejected
+    }
+
+    /// Ownership and permissions of the extracted files, as recorded in the layers
+    pub fn metadata(&self) -> &MetadataOverlay {
+        &self.metadata
+    }
+
+    fn reject(&mut self, path: &Path, reason: impl Into<String>) {
+        let reason = reason.into();
+        warn!("Rejected layer entry {:?}: {}", path, reason);
+        self.rejected.push(RejectedEntry {
+            path: path.to_path_buf(),
+            reason,
+        });
+    }
+
     fn should_exclude(&self, path: &Path) -> bool {
         let path_str = path.to_string_lossy();
         self.exclude_patterns
@@ -37,70 +121,64 @@ impl LayerExtractor {
             .any(|p| p.matches(&path_str) || p.matches_path(path))
     }
 
-    /// Extract a single layer (gzipped tar) to the target directory
+    /// Extract a single layer (gzipped or plain tar) to the target directory
     pub fn extract_layer(&mut self, layer_data: &[u8], target_dir: &Path) -> Result<()> {
-        // First pass: collect whiteouts
-        let decoder = GzDecoder::new(layer_data);
-        let mut archive = Archive::new(decoder);
+        self.extract_layer_from(Cursor::new(layer_data), target_dir)
+    }
 
-        for entry in archive.entries()? {
-            let entry = entry?;
-            let path = entry.path()?;
-
-            if let Some(name) = path.file_name() {
-                let name_str = name.to_string_lossy();
-
-                if name_str == ".wh..wh..opq" {
-                    if let Some(parent) = path.parent() {
-                        debug!("Opaque whiteout for directory: {:?}", parent);
-                        self.opaque_dirs.insert(parent.to_path_buf());
-
-                        // Remove existing directory contents
-                        let full_path = target_dir.join(parent);
-                        if full_path.exists() {
-                            fs::remove_dir_all(&full_path).ok();
-                            fs::create_dir_all(&full_path)?;
-                        }
-                    }
-                } else if name_str.starts_with(".wh.") {
-                    let deleted_name = name_str.strip_prefix(".wh.").unwrap();
-                    let deleted_path = path
-                        .parent()
-                        .map_or_else(|| PathBuf::from(deleted_name), |p| p.join(deleted_name));
-                    debug!("Whiteout for file: {:?}", deleted_path);
-                    self.whiteouts.insert(deleted_path.to_path_buf());
-
-                    let full_path = target_dir.join(&deleted_path);
-                    if full_path.exists() {
-                        if full_path.is_dir() {
-                            fs::remove_dir_all(&full_path).ok();
-                        } else {
-                            fs::remove_file(&full_path).ok();
-                        }
-                    }
-                }
-            }
-        }
+    /// Extract a layer from a stream, detecting its compression
+    pub fn extract_layer_from<R: Read>(&mut self, layer: R, target_dir: &Path) -> Result<()> {
+        self.extract_layer_with(layer, LayerCompression::Detect, target_dir)
+    }
+
+    /// Extract a layer whose compression is known, typically from its media type
+    ///
+    /// The layer is decompressed once and applied as it is read, whiteouts
+    /// included, so it can come straight from a stream.
+    pub fn extract_layer_with<R: Read>(
+        &mut self,
+        layer: R,
+        compression: LayerCompression,
+        target_dir: &Path,
+    ) -> Result<()> {
+        let mut archive = Archive::new(decompress(layer, compression)?);
+        archive.set_preserve_permissions(true);
+        archive.set_preserve_mtime(true);
+        // Don't preserve ownership on extraction (we're not root). Extended
+        // attributes go to the metadata overlay instead: most of them
+        // (security.*, trusted.*) can't be set unprivileged anyway.
+        archive.set_unpack_xattrs(false);
 
-        // Second pass: extract files with proper handling
-        let decoder2 = GzDecoder::new(layer_data);
-        let mut archive2 = Archive::new(decoder2);
-        archive2.set_preserve_permissions(true);
-        archive2.set_preserve_mtime(true);
-        // Don't preserve ownership on extraction (we're not root)
-        archive2.set_unpack_xattrs(false);
+        // Whiteouts only hide lower layers, never what this layer adds
+        let mut layer_paths = HashSet::new();
 
-        for entry in archive2.entries()? {
+        for entry in archive.entries()? {
             let mut entry = entry?;
-            let path = entry.path()?;
-            let path_owned = path.to_path_buf();
+            let raw_path = entry.path()?.into_owned();
+            let Some(path_owned) = entry_path(&raw_path) else {
+                self.reject(&raw_path, "path contains `..`");
+                continue;
+            };
+            let Some(name) = path_owned.file_name() else {
+                continue;
+            };
+            let name_str = name.to_string_lossy();
 
-            // Skip whiteout marker files
-            if let Some(name) = path.file_name() {
-                let name_str = name.to_string_lossy();
-                if name_str.starts_with(".wh.") {
+            if name_str == OPAQUE_WHITEOUT {
+                let dir = path_owned.parent().unwrap_or(Path::new(""));
+                debug!("Opaque whiteout for directory: {:?}", dir);
+                remove_lower_children(target_dir, dir, &layer_paths, &mut self.metadata);
+                continue;
+            }
+            if let Some(deleted_name) = name_str.strip_prefix(WHITEOUT_PREFIX) {
+                if matches!(deleted_name, "" | "." | "..") {
+                    self.reject(&raw_path, "invalid whiteout");
                     continue;
                 }
+                let deleted_path = path_owned.with_file_name(deleted_name);
+                debug!("Whiteout for file: {:?}", deleted_path);
+                remove_lower(target_dir, &deleted_path, &layer_paths, &mut self.metadata);
+                continue;
             }
 
             // Skip excluded paths
@@ -109,39 +187,133 @@ impl LayerExtractor {
                 continue;
             }
 
-            let target_path = target_dir.join(&path_owned);
+            for ancestor in path_owned.ancestors() {
+                if ancestor.as_os_str().is_empty() || !layer_paths.insert(ancestor.to_path_buf()) {
+                    break;
+                }
+            }
+
+            // Parent symlinks are followed inside the rootfs only
+            let target_path = match resolve_in_root(target_dir, &path_owned) {
+                Ok(resolved) => resolved,
+                Err(e) => {
+                    self.reject(&raw_path, format!("{:#}", e));
+                    continue;
+                }
+            };
 
             // Ensure parent directory exists
             if let Some(parent) = target_path.parent() {
                 fs::create_dir_all(parent)?;
             }
 
+            let xattrs = pax_xattrs(&mut entry)?;
+
             // Handle different entry types
-            let entry_type = entry.header().entry_type();
+            let header = entry.header();
+            let entry_type = header.entry_type();
+            // The files on disk belong to us, the archive gets these instead.
+            // Blank owner fields mean root, as they do for GNU tar.
+            let file_metadata = FileMetadata {
+                uid: header.uid().unwrap_or(0) as u32,
+                gid: header.gid().unwrap_or(0) as u32,
+                mode: header.mode()? & 0o7777,
+            };
+            let mut special = special_file(header);
+
+            // Never write through a symlink a lower layer left in place
+            if entry_type != tar::EntryType::Symlink
+                && fs::symlink_metadata(&target_path).is_ok_and(|m| m.file_type().is_symlink())
+            {
+                fs::remove_file(&target_path)?;
+            }
 
             match entry_type {
                 tar::EntryType::Link => {
-                    // Hard link - get the link target and copy instead
-                    if let Ok(Some(link_target)) = entry.link_name() {
-                        let source_path = target_dir.join(link_target.as_ref());
-                        if source_path.exists() {
-                            // Try hard link first, fall back to copy
-                            if fs::hard_link(&source_path, &target_path).is_err() {
-                                fs::copy(&source_path, &target_path).ok();
+                    // Hard link to a file extracted earlier, copied where linking fails
+                    let link_target = entry
+                        .link_name()?
+                        .with_context(|| format!("Hard link {:?} has no target", path_owned))?
+                        .into_owned();
+                    let Some(source) = entry_path(&link_target) else {
+                        self.reject(&raw_path, format!("invalid link target {:?}", link_target));
+                        continue;
+                    };
+                    let source_path = match resolve_in_root(target_dir, &source) {
+                        Ok(resolved) => resolved,
+                        Err(e) => {
+                            self.reject(
+                                &raw_path,
+                                format!("invalid link target {:?}: {:#}", link_target, e),
+                            );
+                            continue;
+                        }
+                    };
+                    let metadata = match fs::symlink_metadata(&source_path) {
+                        Ok(metadata) if !metadata.is_dir() => metadata,
+                        _ => {
+                            self.reject(
+                                &raw_path,
+                                format!("link target {:?} is not a file", link_target),
+                            );
+                            continue;
+                        }
+                    };
+                    // A link to a device node is a device node too
+                    special = source_path
+                        .strip_prefix(target_dir)
+                        .ok()
+                        .and_then(|source| self.metadata.special(source));
+
+                    if source_path != target_path {
+                        match fs::symlink_metadata(&target_path) {
+                            Ok(existing) if existing.is_dir() => fs::remove_dir_all(&target_path)
+                                .with_context(|| {
+                                format!("Failed to replace {:?}", path_owned)
+                            })?,
+                            Ok(_) => fs::remove_file(&target_path)
+                                .with_context(|| format!("Failed to replace {:?}", path_owned))?,
+                            Err(_) => {}
+                        }
+                        // Never follow a symlink when copying
+                        if let Err(e) = fs::hard_link(&source_path, &target_path) {
+                            if !metadata.is_file() {
+                                return Err(e).with_context(|| {
+                                    format!("Failed to extract {:?}", path_owned)
+                                });
                             }
+                            fs::copy(&source_path, &target_path)
+                                .with_context(|| format!("Failed to extract {:?}", path_owned))?;
                         }
                     }
                 }
                 tar::EntryType::Symlink => {
-                    // Symlink - create it
-                    if let Ok(Some(link_target)) = entry.link_name() {
-                        // Remove existing file if any
-                        if target_path.exists() || target_path.is_symlink() {
-                            fs::remove_file(&target_path).ok();
-                        }
-                        #[cfg(unix)]
-                        std::os::unix::fs::symlink(link_target.as_ref(), &target_path).ok();
+                    let link_target = entry
+                        .link_name()?
+                        .with_context(|| format!("Symlink {:?} has no target", path_owned))?
+                        .into_owned();
+                    // Replace whatever a lower layer left there
+                    match fs::symlink_metadata(&target_path) {
+                        Ok(existing) if existing.is_dir() => fs::remove_dir_all(&target_path)
+                            .with_context(|| format!("Failed to replace {:?}", path_owned))?,
+                        Ok(_) => fs::remove_file(&target_path)
+                            .with_context(|| format!("Failed to replace {:?}", path_owned))?,
+                        Err(_) => {}
                     }
+                    #[cfg(unix)]
+                    std::os::unix::fs::symlink(&link_target, &target_path)
+                        .with_context(|| format!("Failed to extract {:?}", path_owned))?;
+                }
+                tar::EntryType::Char | tar::EntryType::Block | tar::EntryType::Fifo => {
+                    // mknod needs root: leave a placeholder whiteouts and hard
+                    // links can act on, the archive gets the node from the overlay
+                    match fs::symlink_metadata(&target_path) {
+                        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&target_path)?,
+                        Ok(_) => fs::remove_file(&target_path)?,
+                        Err(_) => {}
+                    }
+                    fs::File::create(&target_path)
+                        .with_context(|| format!("Failed to extract {:?}", path_owned))?;
                 }
                 _ => {
                     // Regular file or directory - use normal unpack
@@ -150,8 +322,20 @@ impl LayerExtractor {
                         .with_context(|| format!("Failed to extract {:?}", path_owned))?;
                 }
             }
+
+            if let Ok(relative) = target_path.strip_prefix(target_dir) {
+                self.metadata.insert(relative, file_metadata);
+                self.metadata.set_xattrs(relative, xattrs);
+                if let Some(special) = special {
+                    self.metadata.set_special(relative, special);
+                }
+            }
         }
 
+        // Read past the end-of-archive marker so a reader verifying the layer
+        // sees all of it
+        io::copy(&mut archive.into_inner(), &mut io::sink()).context("Failed to read layer")?;
+
         Ok(())
     }
 
@@ -168,6 +352,141 @@ impl LayerExtractor {
     }
 }
 
+/// Device node or FIFO described by `header`, if it is one
+fn special_file(header: &tar::Header) -> Option<SpecialFile> {
+    let major = header.device_major().ok().flatten().unwrap_or(0);
+    let minor = header.device_minor().ok().flatten().unwrap_or(0);
+    match header.entry_type() {
+        tar::EntryType::Char => Some(SpecialFile::CharDevice { major, minor }),
+        tar::EntryType::Block => Some(SpecialFile::BlockDevice { major, minor }),
+        tar::EntryType::Fifo => Some(SpecialFile::Fifo),
+        _ => None,
+    }
+}
+
+/// Extended attributes carried by the `SCHILY.xattr.<name>` PAX records of `entry`
+fn pax_xattrs<R: Read>(entry: &mut tar::Entry<'_, R>) -> Result<Vec<Xattr>> {
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(Vec::new());
+    };
+
+    let mut xattrs = Vec::new();
+    for extension in extensions {
+        let extension = extension?;
+        if let Some(name) = extension
+            .key()
+            .ok()
+            .and_then(|key| key.strip_prefix(PAX_XATTR_PREFIX))
+        {
+            xattrs.push(Xattr {
+                name: name.to_string(),
+                value: extension.value_bytes().to_vec(),
+            });
+        }
+    }
+    Ok(xattrs)
+}
+
+/// Entry path relative to the rootfs, without `.` or leading `/`
+///
+/// `None` for paths with `..` components, which no legitimate layer contains.
+fn entry_path(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => return None,
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+        }
+    }
+    Some(normalized)
+}
+
+/// Remove `path` as lower layers left it, keeping whatever the current layer put inside
+fn remove_lower(
+    target_dir: &Path,
+    path: &Path,
+    layer_paths: &HashSet<PathBuf>,
+    overlay: &mut MetadataOverlay,
+) {
+    let Ok(full_path) = resolve_in_root(target_dir, path) else {
+        return;
+    };
+    if full_path == target_dir {
+        return;
+    }
+    let Ok(metadata) = fs::symlink_metadata(&full_path) else {
+        return;
+    };
+
+    if layer_paths.contains(path) {
+        if metadata.is_dir() {
+            remove_lower_children(target_dir, path, layer_paths, overlay);
+        }
+        return;
+    }
+
+    let removed = if metadata.is_dir() {
+        fs::remove_dir_all(&full_path)
+    } else {
+        fs::remove_file(&full_path)
+    };
+    if let Err(e) = removed {
+        debug!("Failed to remove {:?}: {}", full_path, e);
+    }
+    if let Ok(relative) = full_path.strip_prefix(target_dir) {
+        overlay.remove_tree(relative);
+    }
+}
+
+/// Apply an opaque whiteout: empty `dir` of everything lower layers put there
+fn remove_lower_children(
+    target_dir: &Path,
+    dir: &Path,
+    layer_paths: &HashSet<PathBuf>,
+    overlay: &mut MetadataOverlay,
+) {
+    let Ok(full_path) = resolve_in_root(target_dir, dir) else {
+        return;
+    };
+    // `read_dir` would follow a symlink out of the rootfs
+    if !fs::symlink_metadata(&full_path).is_ok_and(|m| m.is_dir()) {
+        return;
+    }
+    let Ok(children) = fs::read_dir(&full_path) else {
+        return;
+    };
+    for child in children.flatten() {
+        remove_lower(
+            target_dir,
+            &dir.join(child.file_name()),
+            layer_paths,
+            overlay,
+        );
+    }
+}
+
+/// Wrap a layer in the decoder for `compression`
+fn decompress<'a, R: Read + 'a>(
+    layer: R,
+    compression: LayerCompression,
+) -> Result<Box<dyn Read + 'a>> {
+    let mut reader = BufReader::new(layer);
+    let compression = match compression {
+        LayerCompression::Detect => LayerCompression::detect(reader.fill_buf()?),
+        known => known,
+    };
+
+    Ok(match compression {
+        LayerCompression::Gzip => Box::new(GzDecoder::new(reader)),
+        LayerCompression::Zstd => Box::new(
+            zstd::stream::read::Decoder::with_buffer(reader)
+                .context("Failed to initialize zstd decoder")?,
+        ),
+        _ => Box::new(reader),
+    })
+}
+
 impl Default for LayerExtractor {
     fn default() -> Self {
         Self::new()
@@ -188,4 +507,370 @@ mod tests {
         assert!(extractor.should_exclude(Path::new("module.pyc")));
         assert!(!extractor.should_exclude(Path::new("/usr/bin/python")));
     }
+
+    fn build_tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_extract_plain_and_gzip_layers() {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let plain = build_tar(&[("etc/hostname", b"plain")]);
+
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder
+            .write_all(&build_tar(&[("etc/motd", b"gzip")]))
+            .unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        let target = tempfile::TempDir::new().unwrap();
+        LayerExtractor::new()
+            .extract_all_layers(&[plain, gzipped], target.path())
+            .unwrap();
+
+        assert_eq!(
+            fs::read(target.path().join("etc/hostname")).unwrap(),
+            b"plain"
+        );
+        assert_eq!(fs::read(target.path().join("etc/motd")).unwrap(), b"gzip");
+    }
+
+    #[test]
+    fn test_compression_from_media_type() {
+        assert_eq!(
+            LayerCompression::from_media_type("application/vnd.oci.image.layer.v1.tar+zstd")
+                .unwrap(),
+            LayerCompression::Zstd
+        );
+        assert_eq!(
+            LayerCompression::from_media_type("application/vnd.docker.image.rootfs.diff.tar.gzip")
+                .unwrap(),
+            LayerCompression::Gzip
+        );
+        assert_eq!(
+            LayerCompression::from_media_type("application/vnd.docker.image.rootfs.diff.tar")
+                .unwrap(),
+            LayerCompression::Detect
+        );
+        assert!(matches!(
+            LayerCompression::from_media_type(
+                "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip"
+            ),
+            Err(BuilderError::UnsupportedLayer(_))
+        ));
+        assert!(LayerCompression::from_media_type("application/vnd.in-toto+json").is_err());
+    }
+
+    #[test]
+    fn test_extract_zstd_layer() {
+        let tar = build_tar(&[("etc/os-release", b"zstd")]);
+        let compressed = zstd::encode_all(&tar[..], 3).unwrap();
+
+        for compression in [LayerCompression::Zstd, LayerCompression::Detect] {
+            let target = tempfile::TempDir::new().unwrap();
+            LayerExtractor::new()
+                .extract_layer_with(Cursor::new(&compressed), compression, target.path())
+                .unwrap();
+            assert_eq!(
+                fs::read(target.path().join("etc/os-release")).unwrap(),
+                b"zstd"
+            );
+        }
+    }
+
+    #[test]
+    fn test_layer_is_read_to_the_end() {
+        use crate::registry::VerifyingReader;
+        use sha2::{Digest, Sha256};
+
+        // tar pads archives to whole records past the end-of-archive marker
+        let mut tar = build_tar(&[("etc/hostname", b"padded")]);
+        tar.resize(10240, 0);
+        let digest = format!("sha256:{:x}", Sha256::digest(&tar));
+
+        let target = tempfile::TempDir::new().unwrap();
+        let layer = VerifyingReader::new(Cursor::new(&tar), &digest, None).unwrap();
+        LayerExtractor::new()
+            .extract_layer_with(layer, LayerCompression::None, target.path())
+            .unwrap();
+
+        // A mismatch is only noticed once the whole layer has been read
+        let wrong = format!("sha256:{}", "0".repeat(64));
+        let layer = VerifyingReader::new(Cursor::new(&tar), &wrong, None).unwrap();
+        assert!(LayerExtractor::new()
+            .extract_layer_with(layer, LayerCompression::None, target.path())
+            .is_err());
+    }
+
+    #[test]
+    fn test_extract_layer_from_file() {
+        use std::io::{Seek, Write};
+
+        let mut blob = tempfile::tempfile().unwrap();
+        blob.write_all(&build_tar(&[
+            ("usr/bin/tool", b"tool"),
+            ("usr/bin/.wh.old", b""),
+        ]))
+        .unwrap();
+        blob.rewind().unwrap();
+
+        let target = tempfile::TempDir::new().unwrap();
+        fs::create_dir_all(target.path().join("usr/bin")).unwrap();
+        fs::write(target.path().join("usr/bin/old"), b"old").unwrap();
+
+        LayerExtractor::new()
+            .extract_layer_from(blob, target.path())
+            .unwrap();
+
+        assert_eq!(
+            fs::read(target.path().join("usr/bin/tool")).unwrap(),
+            b"tool"
+        );
+        assert!(!target.path().join("usr/bin/old").exists());
+    }
+
+    #[test]
+    fn test_whiteouts_only_hide_lower_layers() {
+        let target = tempfile::TempDir::new().unwrap();
+        let mut extractor = LayerExtractor::new();
+        extractor
+            .extract_layer(
+                &build_tar(&[
+                    ("etc/app/old.conf", b"old"),
+                    ("etc/app/keep/nested", b"old"),
+                    ("etc/passwd", b"root"),
+                    ("usr/lib/libgone.so", b"gone"),
+                ]),
+                target.path(),
+            )
+            .unwrap();
+
+        // Opaque marker listed after this layer's own files in the directory
+        extractor
+            .extract_layer(
+                &build_tar(&[
+                    ("etc/app/new.conf", b"new"),
+                    ("./etc/app/keep/fresh", b"new"),
+                    ("etc/app/.wh..wh..opq", b""),
+                    ("etc/shadow", b"x"),
+                    ("etc/.wh.shadow", b""),
+                    ("usr/lib/.wh.libgone.so", b""),
+                ]),
+                target.path(),
+            )
+            .unwrap();
+
+        let root = target.path();
+        assert_eq!(fs::read(root.join("etc/app/new.conf")).unwrap(), b"new");
+        assert_eq!(fs::read(root.join("etc/app/keep/fresh")).unwrap(), b"new");
+        assert!(!root.join("etc/app/old.conf").exists());
+        assert!(!root.join("etc/app/keep/nested").exists());
+        assert!(root.join("etc/shadow").exists());
+        assert!(root.join("etc/passwd").exists());
+        assert!(!root.join("usr/lib/libgone.so").exists());
+    }
+
+    #[test]
+    fn test_records_image_ownership() {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, uid, mode) in [("etc/shadow", 0, 0o640), ("var/cache/nginx/x", 101, 0o600)] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(1);
+            header.set_uid(uid);
+            header.set_gid(42);
+            header.set_mode(mode);
+            header.set_cksum();
+            builder.append_data(&mut header, name, &b"x"[..]).unwrap();
+        }
+        let layer = builder.into_inner().unwrap();
+
+        let target = tempfile::TempDir::new().unwrap();
+        let mut extractor = LayerExtractor::new();
+        extractor.extract_layer(&layer, target.path()).unwrap();
+        extractor
+            .extract_layer(&build_tar(&[("var/.wh.cache", b"")]), target.path())
+            .unwrap();
+
+        let metadata = extractor.metadata();
+        assert_eq!(
+            metadata.get(Path::new("etc/shadow")),
+            Some(&FileMetadata {
+                uid: 0,
+                gid: 42,
+                mode: 0o640
+            })
+        );
+        assert_eq!(metadata.get(Path::new("var/cache/nginx/x")), None);
+    }
+
+    #[test]
+    fn test_records_pax_xattrs() {
+        let capability: &[u8] = &[0x01, 0x00, 0x00, 0x02, 0x00, 0x20, 0x00, 0x00];
+        let mut builder = tar::Builder::new(Vec::new());
+        builder
+            .append_pax_extensions([
+                ("SCHILY.xattr.security.capability", capability),
+                ("mtime", &b"1700000000"[..]),
+            ])
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o755);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "bin/ping", &b"ping"[..])
+            .unwrap();
+        let layer = builder.into_inner().unwrap();
+
+        let target = tempfile::TempDir::new().unwrap();
+        let mut extractor = LayerExtractor::new();
+        extractor.extract_layer(&layer, target.path()).unwrap();
+
+        let xattrs = extractor.metadata().xattrs();
+        assert_eq!(xattrs.len(), 1);
+        assert_eq!(xattrs[0].0, Path::new("bin/ping"));
+        assert_eq!(
+            xattrs[0].1,
+            [Xattr {
+                name: "security.capability".to_string(),
+                value: capability.to_vec(),
+            }]
+        );
+
+        // A later layer replacing the file drops its attributes
+        extractor
+            .extract_layer(&build_tar(&[("bin/ping", b"new")]), target.path())
+            .unwrap();
+        assert!(extractor.metadata().xattrs().is_empty());
+    }
+
+    #[test]
+    fn test_device_nodes_are_recorded() {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, entry_type, major, minor) in [
+            ("dev/console", tar::EntryType::Char, 5, 1),
+            ("dev/sda", tar::EntryType::Block, 8, 0),
+            ("run/initctl", tar::EntryType::Fifo, 0, 0),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(entry_type);
+            header.set_device_major(major).unwrap();
+            header.set_device_minor(minor).unwrap();
+            header.set_size(0);
+            header.set_mode(0o600);
+            header.set_cksum();
+            builder.append_data(&mut header, name, &b""[..]).unwrap();
+        }
+        let layer = builder.into_inner().unwrap();
+
+        let target = tempfile::TempDir::new().unwrap();
+        let mut extractor = LayerExtractor::new();
+        extractor.extract_layer(&layer, target.path()).unwrap();
+
+        // Only a placeholder exists on disk
+        assert!(target.path().join("dev/console").is_file());
+        let metadata = extractor.metadata();
+        assert_eq!(
+            metadata.special(Path::new("dev/console")),
+            Some(SpecialFile::CharDevice { major: 5, minor: 1 })
+        );
+        assert_eq!(
+            metadata.special(Path::new("dev/sda")),
+            Some(SpecialFile::BlockDevice { major: 8, minor: 0 })
+        );
+        assert_eq!(
+            metadata.special(Path::new("run/initctl")),
+            Some(SpecialFile::Fifo)
+        );
+
+        extractor
+            .extract_layer(&build_tar(&[("dev/.wh.sda", b"")]), target.path())
+            .unwrap();
+        assert!(!target.path().join("dev/sda").exists());
+        assert_eq!(extractor.metadata().special(Path::new("dev/sda")), None);
+    }
+
+    /// Tar with raw entries, bypassing the path checks of `tar::Builder`
+    fn build_raw_tar(entries: &[(&str, tar::EntryType, &str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, entry_type, link, data) in entries {
+            let mut header = tar::Header::new_old();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+            header.set_entry_type(*entry_type);
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_entries_cannot_escape_rootfs() {
+        use tar::EntryType;
+
+        let outside = tempfile::TempDir::new().unwrap();
+        fs::write(outside.path().join("secret"), b"secret").unwrap();
+        let outside_dir = outside.path().to_str().unwrap();
+        let secret = format!("{}/secret", outside_dir);
+
+        let target = tempfile::TempDir::new().unwrap();
+        let mut extractor = LayerExtractor::new();
+        extractor
+            .extract_layer(
+                &build_raw_tar(&[
+                    ("../evil", EntryType::Regular, "", b"evil"),
+                    ("etc", EntryType::Symlink, outside_dir, b""),
+                    ("victim", EntryType::Symlink, outside_dir, b""),
+                    ("stolen", EntryType::Link, &secret, b""),
+                    ("climb", EntryType::Link, "../../../secret", b""),
+                ]),
+                target.path(),
+            )
+            .unwrap();
+        extractor
+            .extract_layer(
+                &build_raw_tar(&[
+                    ("etc/secret", EntryType::Regular, "", b"overwritten"),
+                    ("victim/.wh..wh..opq", EntryType::Regular, "", b""),
+                    (".wh...", EntryType::Regular, "", b""),
+                ]),
+                target.path(),
+            )
+            .unwrap();
+
+        // Nothing outside the rootfs was written, read or removed
+        assert_eq!(fs::read(outside.path().join("secret")).unwrap(), b"secret");
+        assert!(!target.path().parent().unwrap().join("evil").exists());
+        assert!(!target.path().join("stolen").exists());
+        // The symlinked parent resolved inside the rootfs
+        assert_eq!(
+            fs::read(
+                target
+                    .path()
+                    .join(outside_dir.trim_start_matches('/'))
+                    .join("secret")
+            )
+            .unwrap(),
+            b"overwritten"
+        );
+
+        let rejected: Vec<_> = extractor
+            .rejected_entries()
+            .iter()
+            .map(|e| e.path.to_str().unwrap().to_string())
+            .collect();
+        assert_eq!(rejected, ["../evil", "stolen", "climb", ".wh..."]);
+    }
 }
@@ -1,5 +1,14 @@
+mod docker_archive;
+mod docker_daemon;
 mod layer;
+mod oci_layout;
+mod resolve;
 mod rootfs;
+mod source;
 
-pub use layer::LayerExtractor;
+pub use docker_archive::DockerArchive;
+pub use docker_daemon::{DaemonImage, DockerDaemon};
+pub use layer::{LayerCompression, LayerExtractor, RejectedEntry};
+pub use oci_layout::OciLayout;
 pub use rootfs::RootfsBuilder;
+pub use source::{open_source, ImageSource, LayerReader, RegistrySource};
@@ -0,0 +1,384 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use oci_client::manifest::{ImageIndexEntry, OciImageIndex, OciImageManifest};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+use super::{ImageSource, LayerReader};
+use crate::registry::{
+    select_platform, verify_blob, ImageManifest, LayerDescriptor, PullOptions, VerifyingReader,
+};
+
+/// Annotation holding the tag of a manifest in `index.json`
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+const INDEX_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+
+#[derive(Debug, Deserialize)]
+struct LayoutFile {
+    #[serde(rename = "imageLayoutVersion")]
+    image_layout_version: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MediaTypeProbe {
+    #[serde(default, rename = "mediaType")]
+    media_type: Option<String>,
+    #[serde(default)]
+    manifests: Option<serde_json::Value>,
+}
+
+/// An OCI image layout directory (`oci-layout`, `index.json`, `blobs/`)
+pub struct OciLayout {
+    root: PathBuf,
+    tag: Option<String>,
+}
+
+impl OciLayout {
+    /// Open a layout directory, checking its `oci-layout` marker
+    pub fn open(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        let marker = root.join("oci-layout");
+        let data =
+            fs::read(&marker).with_context(|| format!("{:?} is not an OCI image layout", root))?;
+        let layout: LayoutFile = serde_json::from_slice(&data)
+            .with_context(|| format!("Invalid oci-layout file {:?}", marker))?;
+
+        if !layout.image_layout_version.starts_with("1.") {
+            anyhow::bail!(
+                "Unsupported OCI layout version {}",
+                layout.image_layout_version
+            );
+        }
+
+        Ok(Self { root, tag: None })
+    }
+
+    /// Select the image tagged `tag` when used as an [`ImageSource`]
+    pub fn with_tag(mut self, tag: Option<String>) -> Self {
+        self.tag = tag;
+        self
+    }
+
+    /// Parse an `oci:` image argument (`./dir` or `./dir:tag`) into path and tag
+    pub fn parse_reference(spec: &str) -> (PathBuf, Option<String>) {
+        match spec.rsplit_once(':') {
+            Some((path, tag)) if !path.is_empty() && !tag.is_empty() && !tag.contains('/') => {
+                (PathBuf::from(path), Some(tag.to_string()))
+            }
+            _ => (PathBuf::from(spec), None),
+        }
+    }
+
+    /// Resolve the image manifest for `tag` (or the only image when `None`)
+    pub fn fetch_manifest(
+        &self,
+        tag: Option<&str>,
+        options: &PullOptions,
+    ) -> Result<ImageManifest> {
+        let index: OciImageIndex = self.read_json(&self.root.join("index.json"))?;
+
+        let candidates: Vec<ImageIndexEntry> = match tag {
+            Some(tag) => index
+                .manifests
+                .iter()
+                .filter(|m| ref_name(m) == Some(tag))
+                .cloned()
+                .collect(),
+            None => index.manifests.clone(),
+        };
+
+        let entry = match candidates.as_slice() {
+            [] => match tag {
+                Some(tag) => anyhow::bail!("Tag {} not found in {:?}", tag, self.root),
+                None => anyhow::bail!("No manifests in {:?}", self.root),
+            },
+            [single] => single,
+            many if many.iter().any(|m| m.platform.is_some()) => select_platform(many, options)?,
+            _ => anyhow::bail!(
+                "{:?} contains several images, specify one with oci:<path>:<tag>",
+                self.root
+            ),
+        };
+
+        let manifest = self.resolve_manifest(&entry.digest, options)?;
+        Ok(ImageManifest::from_oci(&manifest))
+    }
+
+    /// Follow nested indexes until reaching an image manifest
+    fn resolve_manifest(&self, digest: &str, options: &PullOptions) -> Result<OciImageManifest> {
+        let data = self.read_blob(digest)?;
+        let probe: MediaTypeProbe = serde_json::from_slice(&data)
+            .with_context(|| format!("Invalid manifest blob {}", digest))?;
+
+        let is_index = match probe.media_type.as_deref() {
+            Some(media_type) => INDEX_MEDIA_TYPES.contains(&media_type),
+            None => probe.manifests.is_some(),
+        };
+
+        if is_index {
+            let index: OciImageIndex = serde_json::from_slice(&data)
+                .with_context(|| format!("Invalid image index {}", digest))?;
+            let entry = select_platform(&index.manifests, options)?;
+            debug!("Found platform manifest: {:?}", entry.digest);
+            return self.resolve_manifest(&entry.digest, options);
+        }
+
+        serde_json::from_slice(&data).with_context(|| format!("Invalid image manifest {}", digest))
+    }
+
+    /// Path of a blob inside the layout
+    pub fn blob_path(&self, digest: &str) -> Result<PathBuf> {
+        let (algorithm, hex) = digest
+            .split_once(':')
+            .with_context(|| format!("Invalid digest {}", digest))?;
+        if algorithm.is_empty()
+            || hex.is_empty()
+            || !hex.chars().all(|c| c.is_ascii_alphanumeric())
+            || !algorithm.chars().all(|c| c.is_ascii_alphanumeric())
+        {
+            anyhow::bail!("Invalid digest {}", digest);
+        }
+        Ok(self.root.join("blobs").join(algorithm).join(hex))
+    }
+
+    /// Read a blob by digest, checking its content against the digest
+    pub fn read_blob(&self, digest: &str) -> Result<Vec<u8>> {
+        let path = self.blob_path(digest)?;
+        let data = fs::read(&path).with_context(|| format!("Failed to read blob {:?}", path))?;
+        verify_blob(digest, &data, None)
+            .with_context(|| format!("Blob {:?} failed verification", path))?;
+        Ok(data)
+    }
+
+    fn read_json<T: serde::de::DeserializeOwned>(&self, path: &Path) -> Result<T> {
+        let data = fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+        serde_json::from_slice(&data).with_context(|| format!("Failed to parse {:?}", path))
+    }
+}
+
+#[async_trait]
+impl ImageSource for OciLayout {
+    async fn resolve_manifest(&self, options: &PullOptions) -> Result<ImageManifest> {
+        self.fetch_manifest(self.tag.as_deref(), options)
+    }
+
+    async fn open_layer(
+        &self,
+        layer: &LayerDescriptor,
+        _options: &PullOptions,
+    ) -> Result<LayerReader> {
+        let path = self.blob_path(&layer.digest)?;
+        let file =
+            fs::File::open(&path).with_context(|| format!("Failed to open blob {:?}", path))?;
+        let reader = VerifyingReader::new(file, &layer.digest, Some(layer.size))?;
+        Ok(Box::new(reader))
+    }
+
+    async fn fetch_config(
+        &self,
+        manifest: &ImageManifest,
+        _options: &PullOptions,
+    ) -> Result<Vec<u8>> {
+        self.read_blob(&manifest.config_digest)
+    }
+}
+
+fn ref_name(entry: &ImageIndexEntry) -> Option<&str> {
+    entry
+        .annotations
+        .as_ref()
+        .and_then(|a| a.get(REF_NAME_ANNOTATION))
+        .map(|s| s.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BuilderError;
+    use tempfile::TempDir;
+
+    const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+
+    fn write_blob(root: &Path, data: &[u8]) -> String {
+        use sha2::{Digest, Sha256};
+
+        let hex = format!("{:x}", Sha256::digest(data));
+        let dir = root.join("blobs").join("sha256");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(&hex), data).unwrap();
+        format!("sha256:{}", hex)
+    }
+
+    fn image_manifest(layer_digest: &str) -> String {
+        format!(
+            r#"{{
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "config": {{"mediaType": "application/vnd.oci.image.config.v1+json", "digest": "sha256:c0", "size": 2}},
+                "layers": [{{"mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "digest": "{}", "size": 42}}]
+            }}"#,
+            layer_digest
+        )
+    }
+
+    /// Index entry for a manifest blob, `extra` is appended to its fields
+    fn manifest_entry(media_type: &str, digest: &str, extra: &str) -> String {
+        format!(
+            r#"{{"mediaType": "{}", "digest": "{}", "size": 1{}}}"#,
+            media_type, digest, extra
+        )
+    }
+
+    fn index(entries: &[String]) -> String {
+        format!(
+            r#"{{"schemaVersion": 2, "mediaType": "application/vnd.oci.image.index.v1+json", "manifests": [{}]}}"#,
+            entries.join(", ")
+        )
+    }
+
+    fn create_layout(index: &str) -> TempDir {
+        let dir = TempDir::new().unwrap();
+        fs::write(
+            dir.path().join("oci-layout"),
+            r#"{"imageLayoutVersion": "1.0.0"}"#,
+        )
+        .unwrap();
+        fs::write(dir.path().join("index.json"), index).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_parse_reference() {
+        assert_eq!(
+            OciLayout::parse_reference("./myimage:v1"),
+            (PathBuf::from("./myimage"), Some("v1".to_string()))
+        );
+        assert_eq!(
+            OciLayout::parse_reference("./myimage"),
+            (PathBuf::from("./myimage"), None)
+        );
+        assert_eq!(
+            OciLayout::parse_reference("/tmp/a:b/image"),
+            (PathBuf::from("/tmp/a:b/image"), None)
+        );
+    }
+
+    #[test]
+    fn test_open_requires_marker() {
+        let dir = TempDir::new().unwrap();
+        assert!(OciLayout::open(dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_fetch_manifest_by_tag() {
+        let dir = create_layout("{}");
+        let m1 = write_blob(dir.path(), image_manifest("sha256:l1").as_bytes());
+        let m2 = write_blob(dir.path(), image_manifest("sha256:l2").as_bytes());
+        fs::write(
+            dir.path().join("index.json"),
+            index(&[
+                manifest_entry(
+                    MANIFEST_MEDIA_TYPE,
+                    &m1,
+                    r#", "annotations": {"org.opencontainers.image.ref.name": "v1"}"#,
+                ),
+                manifest_entry(
+                    MANIFEST_MEDIA_TYPE,
+                    &m2,
+                    r#", "annotations": {"org.opencontainers.image.ref.name": "v2"}"#,
+                ),
+            ]),
+        )
+        .unwrap();
+
+        let layout = OciLayout::open(dir.path()).unwrap();
+        let manifest = layout
+            .fetch_manifest(Some("v2"), &PullOptions::default())
+            .unwrap();
+        assert_eq!(manifest.layers.len(), 1);
+        assert_eq!(manifest.layers[0].digest, "sha256:l2");
+        assert_eq!(manifest.total_size, 42);
+
+        assert!(layout
+            .fetch_manifest(Some("v3"), &PullOptions::default())
+            .is_err());
+        assert!(layout
+            .fetch_manifest(None, &PullOptions::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_fetch_manifest_nested_index() {
+        let dir = create_layout("{}");
+        let amd = write_blob(dir.path(), image_manifest("sha256:amdlayer").as_bytes());
+        let arm = write_blob(dir.path(), image_manifest("sha256:armlayer").as_bytes());
+        let nested = write_blob(
+            dir.path(),
+            index(&[
+                manifest_entry(
+                    MANIFEST_MEDIA_TYPE,
+                    &amd,
+                    r#", "platform": {"os": "linux", "architecture": "amd64"}"#,
+                ),
+                manifest_entry(
+                    MANIFEST_MEDIA_TYPE,
+                    &arm,
+                    r#", "platform": {"os": "linux", "architecture": "arm64"}"#,
+                ),
+            ])
+            .as_bytes(),
+        );
+        fs::write(
+            dir.path().join("index.json"),
+            index(&[manifest_entry(INDEX_MEDIA_TYPES[0], &nested, "")]),
+        )
+        .unwrap();
+
+        let layout = OciLayout::open(dir.path()).unwrap();
+        let options = PullOptions {
+            platform_arch: "arm64".to_string(),
+            ..Default::default()
+        };
+        let manifest = layout.fetch_manifest(None, &options).unwrap();
+        assert_eq!(manifest.layers[0].digest, "sha256:armlayer");
+    }
+
+    #[test]
+    fn test_corrupted_blob_is_rejected() {
+        let dir = create_layout("{}");
+        let digest = write_blob(dir.path(), image_manifest("sha256:l1").as_bytes());
+        fs::write(
+            dir.path().join("index.json"),
+            index(&[manifest_entry(MANIFEST_MEDIA_TYPE, &digest, "")]),
+        )
+        .unwrap();
+
+        let layout = OciLayout::open(dir.path()).unwrap();
+        fs::write(layout.blob_path(&digest).unwrap(), b"tampered").unwrap();
+
+        let err = layout
+            .fetch_manifest(None, &PullOptions::default())
+            .unwrap_err();
+        assert!(err.chain().any(|e| matches!(
+            e.downcast_ref::<BuilderError>(),
+            Some(BuilderError::DigestMismatch { .. })
+        )));
+    }
+
+    #[test]
+    fn test_blob_path_rejects_traversal() {
+        let dir = create_layout(r#"{"schemaVersion": 2, "manifests": []}"#);
+        let layout = OciLayout::open(dir.path()).unwrap();
+        assert!(layout.blob_path("sha256:../../etc/passwd").is_err());
+        assert_eq!(
+            layout.blob_path("sha256:abc").unwrap(),
+            dir.path().join("blobs/sha256/abc")
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Same limit as Linux (`MAXSYMLINKS`)
+const MAX_SYMLINKS: usize = 40;
+
+enum Step {
+    Root,
+    Parent,
+    Name(OsString),
+}
+
+fn steps(path: &Path) -> impl DoubleEndedIterator<Item = Step> + '_ {
+    path.components().filter_map(|component| match component {
+        Component::Prefix(_) | Component::RootDir => Some(Step::Root),
+        Component::CurDir => None,
+        Component::ParentDir => Some(Step::Parent),
+        Component::Normal(name) => Some(Step::Name(name.to_os_string())),
+    })
+}
+
+/// Resolve `path` inside `root` the way a process chrooted into `root` would
+///
+/// Symlinks met on the way are followed relative to `root` (absolute targets
+/// restart from `root`) and `..` never climbs above it, so the result always
+/// lies inside `root`. The last component is not followed, so the result can be
+/// created, replaced or removed without touching anything outside.
+pub(crate) fn resolve_in_root(root: &Path, path: &Path) -> Result<PathBuf> {
+    let mut resolved = root.to_path_buf();
+    let mut depth = 0;
+    let mut pending: VecDeque<Step> = steps(path).collect();
+    let mut links = 0;
+
+    while let Some(step) = pending.pop_front() {
+        match step {
+            Step::Root => {
+                resolved = root.to_path_buf();
+                depth = 0;
+            }
+            Step::Parent => {
+                if depth > 0 {
+                    resolved.pop();
+                    depth -= 1;
+                }
+            }
+            Step::Name(name) => {
+                resolved.push(&name);
+                depth += 1;
+
+                let is_symlink = fs::symlink_metadata(&resolved)
+                    .map(|m| m.file_type().is_symlink())
+                    .unwrap_or(false);
+                if is_symlink && !pending.is_empty() {
+                    links += 1;
+                    if links > MAX_SYMLINKS {
+                        anyhow::bail!("Too many levels of symbolic links in {:?}", path);
+                    }
+
+                    let target = fs::read_link(&resolved)?;
+                    resolved.pop();
+                    depth -= 1;
+                    for step in steps(&target).rev() {
+                        pending.push_front(step);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_resolve_in_root() {
+        let root = tempfile::TempDir::new().unwrap();
+        let root = root.path();
+        fs::create_dir_all(root.join("usr/lib")).unwrap();
+        symlink("usr/lib", root.join("lib")).unwrap();
+        symlink("/etc", root.join("usr/lib/etc")).unwrap();
+        symlink("../../../../..", root.join("escape")).unwrap();
+        symlink("loop", root.join("loop")).unwrap();
+
+        let resolve = |path: &str| resolve_in_root(root, Path::new(path)).unwrap();
+
+        assert_eq!(resolve("lib/libc.so"), root.join("usr/lib/libc.so"));
+        assert_eq!(resolve("/lib/etc/passwd"), root.join("etc/passwd"));
+        assert_eq!(resolve("escape/etc/passwd"), root.join("etc/passwd"));
+        assert_eq!(resolve("../../etc/shadow"), root.join("etc/shadow"));
+        // The last component is never followed
+        assert_eq!(resolve("lib"), root.join("lib"));
+        assert!(resolve_in_root(root, Path::new("loop/x")).is_err());
+    }
+}
@@ -1,25 +1,42 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use futures::stream::{self, StreamExt};
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use tempfile::TempDir;
-use tracing::info;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
 
-use super::LayerExtractor;
-use crate::registry::{PullOptions, RegistryClient};
+use super::{ImageSource, LayerCompression, LayerExtractor, RejectedEntry};
+use crate::initramfs::MetadataOverlay;
+use crate::registry::{ImageManifest, PullOptions};
 
 pub struct RootfsBuilder {
-    client: RegistryClient,
+    source: Box<dyn ImageSource>,
     options: PullOptions,
     exclude_patterns: Vec<String>,
     temp_dir: Option<TempDir>,
+    rejected: Vec<RejectedEntry>,
+    metadata: MetadataOverlay,
+    manifest: Option<ImageManifest>,
+}
+
+/// The part of the image config read here
+#[derive(Deserialize)]
+struct ImageConfig {
+    created: Option<String>,
 }
 
 impl RootfsBuilder {
-    pub fn new(client: RegistryClient) -> Self {
+    pub fn new(source: Box<dyn ImageSource>) -> Self {
         Self {
-            client,
+            source,
             options: PullOptions::default(),
             exclude_patterns: Vec::new(),
             temp_dir: None,
+            rejected: Vec::new(),
+            metadata: MetadataOverlay::new(),
+            manifest: None,
         }
     }
 
@@ -29,20 +46,22 @@ impl RootfsBuilder {
         self
     }
 
+    /// Replace the pull options (platform, mirrors, ...)
+    pub fn pull_options(mut self, options: PullOptions) -> Self {
+        self.options = options;
+        self
+    }
+
     pub fn exclude(mut self, patterns: &[&str]) -> Self {
         self.exclude_patterns
             .extend(patterns.iter().map(|s| s.to_string()));
         self
     }
 
-    pub async fn build(&mut self, image: &str) -> Result<PathBuf> {
-        let reference = RegistryClient::parse_reference(image)?;
-
-        info!("Fetching manifest for {}", image);
-        let manifest = self
-            .client
-            .fetch_manifest(&reference, &self.options)
-            .await?;
+    /// Resolve the image from the source and extract its layers
+    pub async fn build(&mut self) -> Result<PathBuf> {
+        info!("Resolving image manifest");
+        let manifest = self.source.resolve_manifest(&self.options).await?;
 
         info!(
             "Image has {} layers, total size: {} bytes",
@@ -50,21 +69,65 @@ impl RootfsBuilder {
             manifest.total_size
         );
 
-        info!("Pulling layers...");
-        let layers = self
-            .client
-            .pull_all_layers(&reference, &manifest, None)
-            .await?;
+        // Fail before downloading anything if a layer cannot be extracted
+        let compressions = manifest
+            .layers
+            .iter()
+            .map(|layer| LayerCompression::from_media_type(&layer.media_type))
+            .collect::<Result<Vec<_>, _>>()?;
 
         let temp_dir = TempDir::new()?;
         let rootfs_path = temp_dir.path().to_path_buf();
 
-        info!("Extracting layers to {:?}", rootfs_path);
         let exclude_refs: Vec<&str> = self.exclude_patterns.iter().map(|s| s.as_str()).collect();
         let mut extractor = LayerExtractor::new().with_excludes(&exclude_refs)?;
-        extractor.extract_all_layers(&layers, &rootfs_path)?;
+
+        info!("Extracting layers to {:?}", rootfs_path);
+        let total = manifest.layers.len();
+        // A layer repeated in the manifest (empty layers often are) is opened one
+        // at a time, so a blob cache downloads it once
+        let in_flight: HashMap<String, Mutex<()>> = manifest
+            .layers
+            .iter()
+            .map(|layer| (layer.digest.clone(), Mutex::new(())))
+            .collect();
+
+        // Owned descriptors keep the download futures `Send`
+        let (source, options, in_flight) = (&self.source, &self.options, &in_flight);
+        let mut readers = stream::iter(manifest.layers.clone())
+            .map(|layer| async move {
+                let _opening = in_flight[&layer.digest].lock().await;
+                source.open_layer(&layer, options).await
+            })
+            .buffered(options.max_concurrent_downloads.max(1));
+
+        let mut next = readers.next().await;
+        let mut compressions = compressions.into_iter();
+        let mut extracted = 0;
+        while let (Some(reader), Some(compression)) = (next, compressions.next()) {
+            let reader = reader?;
+            extracted += 1;
+            debug!(
+                "Extracting layer {}/{} ({:?})",
+                extracted, total, compression
+            );
+
+            let target = rootfs_path.clone();
+            let extraction = tokio::task::spawn_blocking(move || {
+                extractor.extract_layer_with(reader, compression, &target)?;
+                Ok::<_, anyhow::Error>(extractor)
+            });
+
+            // Keep the following downloads going while this layer is extracted
+            let (result, following) = futures::join!(extraction, readers.next());
+            extractor = result??;
+            next = following;
+        }
 
         self.temp_dir = Some(temp_dir);
+        self.rejected = extractor.rejected_entries().to_vec();
+        self.metadata = extractor.metadata().clone();
+        self.manifest = Some(manifest.clone());
 
         Ok(rootfs_path)
     }
@@ -72,4 +135,237 @@ impl RootfsBuilder {
     pub fn rootfs_path(&self) -> Option<&Path> {
         self.temp_dir.as_ref().map(|t| t.path())
     }
+
+    /// Ownership and permissions of the rootfs files as the image defines them
+    pub fn metadata(&self) -> &MetadataOverlay {
+        &self.metadata
+    }
+
+    /// Layer entries skipped by the last build because they tried to escape the rootfs
+    pub fn rejected_entries(&self) -> &[RejectedEntry] {
+        &self.rejected
+    }
+
+    /// Creation time of the built image in seconds since the Unix epoch, from
+    /// the `created` field of its config
+    pub async fn created(&self) -> Result<Option<u64>> {
+        let manifest = self
+            .manifest
+            .as_ref()
+            .context("The image has not been built yet")?;
+        let raw_config = self.source.fetch_config(manifest, &self.options).await?;
+        let config: ImageConfig =
+            serde_json::from_slice(&raw_config).context("Invalid image config")?;
+
+        let created = config.created.as_deref().and_then(|created| {
+            let parsed = parse_rfc3339(created);
+            if parsed.is_none() {
+                warn!("Ignoring invalid image creation date {:?}", created);
+            }
+            parsed
+        });
+        Ok(created)
+    }
+}
+
+/// Seconds since the Unix epoch of an RFC 3339 timestamp (`2024-01-02T03:04:05.5Z`)
+fn parse_rfc3339(value: &str) -> Option<u64> {
+    let time = chrono::DateTime::parse_from_rfc3339(value).ok()?;
+    u64::try_from(time.timestamp()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::LayerReader;
+    use crate::registry::{ImageManifest, LayerDescriptor};
+    use async_trait::async_trait;
+    use std::collections::HashSet;
+    use std::io::Cursor;
+
+    /// Image kept in memory as (digest, blob) pairs
+    #[derive(Default)]
+    struct MemorySource {
+        layers: Vec<(String, Vec<u8>)>,
+        config: Vec<u8>,
+        /// Digests being opened, to catch concurrent opens of a repeated layer
+        opening: std::sync::Mutex<HashSet<String>>,
+    }
+
+    #[async_trait]
+    impl ImageSource for MemorySource {
+        async fn resolve_manifest(&self, _options: &PullOptions) -> Result<ImageManifest> {
+            let layers: Vec<LayerDescriptor> = self
+                .layers
+                .iter()
+                .map(|(digest, data)| LayerDescriptor {
+                    digest: digest.clone(),
+                    size: data.len() as u64,
+                    media_type: "application/vnd.oci.image.layer.v1.tar".to_string(),
+                })
+                .collect();
+            Ok(ImageManifest {
+                config_digest: "sha256:config".to_string(),
+                config_size: 2,
+                total_size: layers.iter().map(|l| l.size).sum(),
+                layers,
+            })
+        }
+
+        async fn open_layer(
+            &self,
+            layer: &LayerDescriptor,
+            _options: &PullOptions,
+        ) -> Result<LayerReader> {
+            let position = self
+                .layers
+                .iter()
+                .position(|(digest, _)| *digest == layer.digest)
+                .ok_or_else(|| anyhow::anyhow!("unknown layer {}", layer.digest))?;
+            if !self.opening.lock().unwrap().insert(layer.digest.clone()) {
+                anyhow::bail!("{} opened twice at the same time", layer.digest);
+            }
+
+            // Lower layers take longest, so concurrent downloads finish out of order
+            let delay = (self.layers.len() - position) as u64 * 10;
+            tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+
+            self.opening.lock().unwrap().remove(&layer.digest);
+            Ok(Box::new(Cursor::new(self.layers[position].1.clone())))
+        }
+
+        async fn fetch_config(
+            &self,
+            _manifest: &ImageManifest,
+            _options: &PullOptions,
+        ) -> Result<Vec<u8>> {
+            Ok(self.config.clone())
+        }
+    }
+
+    fn layer_with(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_build_from_custom_source() {
+        let source = MemorySource {
+            layers: vec![
+                (
+                    "sha256:base".to_string(),
+                    layer_with(&[("etc/hostname", b"base"), ("bin/sh", b"sh")]),
+                ),
+                (
+                    "sha256:top".to_string(),
+                    layer_with(&[("etc/hostname", b"top")]),
+                ),
+            ],
+            config: b"{}".to_vec(),
+            ..Default::default()
+        };
+
+        let mut builder = RootfsBuilder::new(Box::new(source))
+            .pull_options(PullOptions::default().concurrent_downloads(2));
+        let rootfs = builder.build().await.unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(rootfs.join("etc/hostname")).unwrap(),
+            "top"
+        );
+        assert!(rootfs.join("bin/sh").exists());
+        assert_eq!(builder.rootfs_path(), Some(rootfs.as_path()));
+    }
+
+    #[tokio::test]
+    async fn test_repeated_layer_is_opened_once_at_a_time() {
+        let empty = layer_with(&[]);
+        let source = MemorySource {
+            layers: vec![
+                ("sha256:empty".to_string(), empty.clone()),
+                (
+                    "sha256:app".to_string(),
+                    layer_with(&[("app/main", b"main")]),
+                ),
+                ("sha256:empty".to_string(), empty),
+            ],
+            config: b"{}".to_vec(),
+            ..Default::default()
+        };
+
+        let mut builder = RootfsBuilder::new(Box::new(source))
+            .pull_options(PullOptions::default().concurrent_downloads(3));
+        let rootfs = builder.build().await.unwrap();
+        assert!(rootfs.join("app/main").exists());
+    }
+
+    #[tokio::test]
+    async fn test_image_created() {
+        let source = MemorySource {
+            layers: vec![(
+                "sha256:base".to_string(),
+                layer_with(&[("etc/os-release", b"")]),
+            )],
+            config: br#"{"created": "2024-01-02T03:04:05.123456789Z"}"#.to_vec(),
+            ..Default::default()
+        };
+        let mut builder = RootfsBuilder::new(Box::new(source));
+        assert!(builder.created().await.is_err());
+
+        builder.build().await.unwrap();
+        assert_eq!(builder.created().await.unwrap(), Some(1_704_164_645));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_image_created_is_ignored() {
+        let source = MemorySource {
+            layers: vec![(
+                "sha256:base".to_string(),
+                layer_with(&[("etc/os-release", b"")]),
+            )],
+            config: br#"{"created": "last tuesday"}"#.to_vec(),
+            ..Default::default()
+        };
+        let mut builder = RootfsBuilder::new(Box::new(source));
+        builder.build().await.unwrap();
+        assert_eq!(builder.created().await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_parse_rfc3339() {
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_rfc3339("2000-02-29T12:00:00Z"), Some(951_825_600));
+        assert_eq!(
+            parse_rfc3339("2024-01-02T05:04:05.5+02:00"),
+            Some(1_704_164_645)
+        );
+        assert_eq!(
+            parse_rfc3339("2024-01-01T22:04:05-05:00"),
+            Some(1_704_164_645)
+        );
+        // Nanosecond precision, as written by Docker, and explicit UTC offsets
+        assert_eq!(
+            parse_rfc3339("2024-01-02T03:04:05.123456789Z"),
+            Some(1_704_164_645)
+        );
+        assert_eq!(
+            parse_rfc3339("2024-01-02t03:04:05+00:00"),
+            Some(1_704_164_645)
+        );
+        assert_eq!(
+            parse_rfc3339("2024-01-02T08:34:05.5+05:30"),
+            Some(1_704_164_645)
+        );
+        assert_eq!(parse_rfc3339("2024-13-02T03:04:05Z"), None);
+        assert_eq!(parse_rfc3339("2024-01-02T03:04:05"), None);
+        assert_eq!(parse_rfc3339("1969-12-31T23:59:59Z"), None);
+        assert_eq!(parse_rfc3339("yesterday"), None);
+    }
 }
@@ -0,0 +1,109 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use oci_client::Reference;
+use std::fs::File;
+use std::io::Read;
+use tempfile::NamedTempFile;
+
+use super::{DaemonImage, DockerArchive, DockerDaemon, OciLayout};
+use crate::registry::{ImageManifest, LayerDescriptor, PullOptions, RegistryClient};
+
+/// Readable stream over a (possibly compressed) layer blob
+pub type LayerReader = Box<dyn Read + Send>;
+
+/// Somewhere an image can be read from: a registry, an OCI layout, a tarball...
+///
+/// Implement this to build initramfs images from your own artifact store and
+/// hand it to [`crate::InitramfsBuilder::source`].
+#[async_trait]
+pub trait ImageSource: Send + Sync {
+    /// Resolve the image manifest for the requested platform
+    async fn resolve_manifest(&self, options: &PullOptions) -> Result<ImageManifest>;
+
+    /// Open the blob of one of the manifest's layers
+    async fn open_layer(
+        &self,
+        layer: &LayerDescriptor,
+        options: &PullOptions,
+    ) -> Result<LayerReader>;
+
+    /// Fetch the raw image config blob (JSON)
+    async fn fetch_config(
+        &self,
+        manifest: &ImageManifest,
+        options: &PullOptions,
+    ) -> Result<Vec<u8>>;
+}
+
+/// An image in an OCI registry
+pub struct RegistrySource {
+    client: RegistryClient,
+    reference: Reference,
+}
+
+impl RegistrySource {
+    pub fn new(client: RegistryClient, image: &str) -> Result<Self> {
+        Ok(Self {
+            client,
+            reference: RegistryClient::parse_reference(image)?,
+        })
+    }
+}
+
+#[async_trait]
+impl ImageSource for RegistrySource {
+    async fn resolve_manifest(&self, options: &PullOptions) -> Result<ImageManifest> {
+        self.client.fetch_manifest(&self.reference, options).await
+    }
+
+    async fn open_layer(
+        &self,
+        layer: &LayerDescriptor,
+        options: &PullOptions,
+    ) -> Result<LayerReader> {
+        if self.client.has_cache() {
+            let path = self
+                .client
+                .pull_layer_to_cache(&self.reference, layer, options)
+                .await?;
+            return Ok(Box::new(File::open(path)?));
+        }
+
+        // Unlinked once dropped, the reopened handle stays readable
+        let blob = NamedTempFile::new()?;
+        self.client
+            .pull_layer_to_file(&self.reference, layer, options, blob.path())
+            .await?;
+        Ok(Box::new(blob.reopen()?))
+    }
+
+    async fn fetch_config(
+        &self,
+        manifest: &ImageManifest,
+        options: &PullOptions,
+    ) -> Result<Vec<u8>> {
+        self.client
+            .fetch_config(&self.reference, manifest, options)
+            .await
+    }
+}
+
+/// Open the source for an image argument
+///
+/// `image` is a registry reference, `oci:<path>[:<tag>]` for an OCI image
+/// layout directory on disk, `docker-archive:<path>[:<repo>:<tag>]` for a
+/// `docker save` tarball, or `docker-daemon:<image>` for an image from the
+/// local Docker/Podman daemon. `client` is only used for registry references.
+pub fn open_source(image: &str, client: RegistryClient) -> Result<Box<dyn ImageSource>> {
+    if let Some(spec) = image.strip_prefix("oci:") {
+        let (path, tag) = OciLayout::parse_reference(spec);
+        Ok(Box::new(OciLayout::open(path)?.with_tag(tag)))
+    } else if let Some(spec) = image.strip_prefix("docker-archive:") {
+        let (path, tag) = DockerArchive::parse_reference(spec);
+        Ok(Box::new(DockerArchive::open(path, tag.as_deref())?))
+    } else if let Some(name) = image.strip_prefix("docker-daemon:") {
+        Ok(Box::new(DaemonImage::new(DockerDaemon::from_env()?, name)))
+    } else {
+        Ok(Box::new(RegistrySource::new(client, image)?))
+    }
+}
@@ -129,6 +129,25 @@ mod tests {
         assert_eq!(decompressed, data);
     }
 
+    #[test]
+    fn test_gzip_output_is_deterministic() {
+        let temp_dir = TempDir::new().unwrap();
+        let data = b"070701".repeat(50);
+        let outputs: Vec<Vec<u8>> = ["a.gz", "b.gz"]
+            .iter()
+            .map(|name| {
+                let path = temp_dir.path().join(name);
+                compress_archive(&data, &path, Compression::Gzip).unwrap();
+                fs::read(path).unwrap()
+            })
+            .collect();
+
+        assert_eq!(outputs[0], outputs[1]);
+        // flate2 writes no timestamp and an unknown OS
+        assert_eq!(&outputs[0][4..8], &[0, 0, 0, 0]);
+        assert_eq!(outputs[0][9], 255);
+    }
+
     #[test]
     fn test_zstd_compression() {
         let temp_dir = TempDir::new().unwrap();
@@ -1,13 +1,59 @@
 use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
 use std::fs::{self};
 use std::io::Write;
-use std::os::unix::fs::{MetadataExt, PermissionsExt};
-use std::path::Path;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Component, Path};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tracing::debug;
 use walkdir::WalkDir;
 
+use super::metadata::{FileMetadata, MetadataOverlay, SpecialFile};
+use super::owner::OwnerMap;
+
 pub struct CpioArchive {
     entries: Vec<CpioEntry>,
+    /// Index in `entries` of each archive path
+    paths: HashMap<String, usize>,
+    /// Host (device, inode) of the hard linked files whose data has been read
+    read_links: HashSet<(u64, u64)>,
+    owners: OwnerMap,
+    /// Set in reproducible mode, see [`CpioArchive::set_reproducible`]
+    source_date_epoch: Option<u32>,
+}
+
+/// Permissions, owner and mtime of an entry added with the `add_*` methods
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryAttributes {
+    /// Permission bits (`0o7777`), the file type comes from the method used
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Seconds since the Unix epoch
+    pub mtime: u32,
+}
+
+impl EntryAttributes {
+    /// Root-owned entry with permissions `mode`, modified now
+    pub fn new(mode: u32) -> Self {
+        Self {
+            mode,
+            uid: 0,
+            gid: 0,
+            mtime: now(),
+        }
+    }
+
+    pub fn owner(mut self, uid: u32, gid: u32) -> Self {
+        self.uid = uid;
+        self.gid = gid;
+        self
+    }
+
+    pub fn mtime(mut self, mtime: u32) -> Self {
+        self.mtime = mtime;
+        self
+    }
 }
 
 struct CpioEntry {
@@ -22,17 +68,49 @@ struct CpioEntry {
     dev_minor: u32,
     rdev_major: u32,
     rdev_minor: u32,
+    /// Host (device, inode) of a regular file with other hard links
+    link: Option<(u64, u64)>,
+    /// Owner was taken from the host, nothing recorded it
+    host_owner: bool,
 }
 
 impl CpioArchive {
     pub fn new() -> Self {
         Self {
             entries: Vec::new(),
+            paths: HashMap::new(),
+            read_links: HashSet::new(),
+            owners: OwnerMap::new(),
+            source_date_epoch: None,
         }
     }
 
-    /// Build a CPIO archive from a directory
+    /// Write the archive deterministically
+    ///
+    /// Entries are written sorted by path (which keeps parents first) and
+    /// modification times later than `source_date_epoch` are clamped to it.
+    /// Directories get a link count of 2, which otherwise depends on the host
+    /// filesystem. Host files nothing recorded the owner of are written root-owned,
+    /// unless an [`OwnerMap`] rule covers them, as their owner is the build user.
+    /// Permissions, special bits included, are written as they are.
+    /// Inode numbers are always assigned in write order, never taken from the host.
+    pub fn set_reproducible(&mut self, source_date_epoch: u32) {
+        self.source_date_epoch = Some(source_date_epoch);
+    }
+
+    /// Rewrite the owner of the entries as they are written
+    pub fn set_owners(&mut self, owners: OwnerMap) {
+        self.owners = owners;
+    }
+
+    /// Build a CPIO archive from a directory, with the ownership of the files on disk
     pub fn from_directory(root: &Path) -> Result<Self> {
+        Self::from_directory_with_metadata(root, &MetadataOverlay::new())
+    }
+
+    /// Build a CPIO archive from a directory, taking ownership and permissions
+    /// from `overlay` for the paths it knows (those extracted from image layers)
+    pub fn from_directory_with_metadata(root: &Path, overlay: &MetadataOverlay) -> Result<Self> {
         let mut archive = Self::new();
 
         for entry in WalkDir::new(root).follow_links(false) {
@@ -47,21 +125,59 @@ impl CpioArchive {
 
             let archive_path = format!("{}", rel_path.display());
 
-            archive.add_path(full_path, &archive_path)?;
+            archive.add_path(
+                full_path,
+                &archive_path,
+                overlay.get(rel_path),
+                overlay.special(rel_path),
+            )?;
         }
 
         Ok(archive)
     }
 
     /// Add a file or directory to the archive
-    fn add_path(&mut self, source_path: &Path, archive_path: &str) -> Result<()> {
+    ///
+    /// With `special`, the file on disk is only a placeholder for a device node
+    /// or FIFO extracted from a layer.
+    fn add_path(
+        &mut self,
+        source_path: &Path,
+        archive_path: &str,
+        recorded: Option<&FileMetadata>,
+        special: Option<SpecialFile>,
+    ) -> Result<()> {
         let metadata = fs::symlink_metadata(source_path)
             .with_context(|| format!("Failed to read metadata for {:?}", source_path))?;
 
         let file_type = metadata.file_type();
-        let mode = metadata.permissions().mode();
+        if let (Some(special), Some(recorded)) = (special, recorded) {
+            let attributes = EntryAttributes::new(recorded.mode)
+                .owner(recorded.uid, recorded.gid)
+                .mtime(metadata.mtime() as u32);
+            self.add_special(archive_path, special, attributes);
+            return Ok(());
+        }
+
+        let (mode, uid, gid) = match recorded {
+            Some(recorded) => (
+                (metadata.mode() & S_IFMT) | recorded.mode,
+                recorded.uid,
+                recorded.gid,
+            ),
+            None => (
+                metadata.permissions().mode(),
+                metadata.uid(),
+                metadata.gid(),
+            ),
+        };
+
+        // The data of hard linked files is read once, for the first link
+        let link =
+            (file_type.is_file() && metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()));
+        let linked = link.is_some_and(|link| !self.read_links.insert(link));
 
-        let data = if file_type.is_file() {
+        let data = if file_type.is_file() && !linked {
             fs::read(source_path)?
         } else if file_type.is_symlink() {
             let target = fs::read_link(source_path)?;
@@ -69,6 +185,12 @@ impl CpioArchive {
         } else {
             Vec::new()
         };
+        let (rdev_major, rdev_minor) = if file_type.is_char_device() || file_type.is_block_device()
+        {
+            split_dev(metadata.rdev())
+        } else {
+            (0, 0)
+        };
 
         debug!(
             "Adding to cpio: {} (mode: {:o}, size: {})",
@@ -77,30 +199,274 @@ impl CpioArchive {
             data.len()
         );
 
-        self.entries.push(CpioEntry {
+        self.push(CpioEntry {
             path: archive_path.to_string(),
             mode,
-            uid: metadata.uid(),
-            gid: metadata.gid(),
-            nlink: metadata.nlink() as u32,
+            uid,
+            gid,
+            // Links within the archive are counted when it is written
+            nlink: if file_type.is_file() {
+                1
+            } else {
+                metadata.nlink() as u32
+            },
             mtime: metadata.mtime() as u32,
             data,
             dev_major: 0,
             dev_minor: 0,
-            rdev_major: 0,
-            rdev_minor: 0,
+            rdev_major,
+            rdev_minor,
+            link,
+            host_owner: recorded.is_none(),
         });
 
         Ok(())
     }
 
+    /// Whether the archive has an entry at `path`
+    pub fn contains(&self, path: impl AsRef<Path>) -> bool {
+        let archive_path = normalize_path(path.as_ref());
+        self.paths.contains_key(&archive_path)
+    }
+
+    fn entry(&self, archive_path: &str) -> Option<&CpioEntry> {
+        self.paths
+            .get(archive_path)
+            .map(|&index| &self.entries[index])
+    }
+
+    fn push(&mut self, entry: CpioEntry) {
+        self.paths.insert(entry.path.clone(), self.entries.len());
+        self.entries.push(entry);
+    }
+
+    /// Archive path of the entry `path` leads to, following the symlinks in the
+    /// archive as the kernel would after unpacking it, if there is one
+    pub fn resolve(&self, path: impl AsRef<Path>) -> Option<String> {
+        let mut pending: Vec<String> = normalize_path(path.as_ref())
+            .rsplit('/')
+            .map(str::to_string)
+            .collect();
+        let mut resolved: Vec<String> = Vec::new();
+        let mut symlinks = 0;
+
+        while let Some(component) = pending.pop() {
+            match component.as_str() {
+                "" | "." => continue,
+                ".." => {
+                    resolved.pop();
+                    continue;
+                }
+                _ => {}
+            }
+
+            let candidate = if resolved.is_empty() {
+                component.clone()
+            } else {
+                format!("{}/{}", resolved.join("/"), component)
+            };
+            let entry = self.entry(&candidate)?;
+            if entry.mode & S_IFMT != S_IFLNK {
+                resolved.push(component);
+                continue;
+            }
+
+            // Same limit as the kernel
+            symlinks += 1;
+            if symlinks > 40 {
+                return None;
+            }
+            let target = String::from_utf8_lossy(&entry.data);
+            if target.starts_with('/') {
+                resolved.clear();
+            }
+            pending.extend(target.rsplit('/').map(str::to_string));
+        }
+
+        Some(resolved.join("/"))
+    }
+
+    /// Add a directory at `path`, replacing any entry already there
+    ///
+    /// Like the other `add_*` methods, nothing is read from or written to the
+    /// host, and parent directories missing from the archive are added with
+    /// mode 0755, owned by root.
+    pub fn add_directory(&mut self, path: impl AsRef<Path>, attributes: EntryAttributes) {
+        self.insert_entry(path.as_ref(), S_IFDIR, attributes, Vec::new(), (0, 0));
+    }
+
+    /// Add a regular file holding `data` at `path`, replacing any entry already there
+    pub fn add_file(
+        &mut self,
+        path: impl AsRef<Path>,
+        data: impl Into<Vec<u8>>,
+        attributes: EntryAttributes,
+    ) {
+        self.insert_entry(path.as_ref(), S_IFREG, attributes, data.into(), (0, 0));
+    }
+
+    /// Add a symlink at `path` pointing to `target`, replacing any entry already there
+    pub fn add_symlink(
+        &mut self,
+        path: impl AsRef<Path>,
+        target: impl AsRef<Path>,
+        attributes: EntryAttributes,
+    ) {
+        let target = target.as_ref().to_string_lossy().into_owned().into_bytes();
+        self.insert_entry(path.as_ref(), S_IFLNK, attributes, target, (0, 0));
+    }
+
+    /// Add a device node or FIFO at `path`, replacing any entry already there
+    ///
+    /// No `mknod` happens on the host, so no privileges are needed.
+    pub fn add_special(
+        &mut self,
+        path: impl AsRef<Path>,
+        special: SpecialFile,
+        attributes: EntryAttributes,
+    ) {
+        let (file_type, rdev) = match special {
+            SpecialFile::CharDevice { major, minor } => (S_IFCHR, (major, minor)),
+            SpecialFile::BlockDevice { major, minor } => (S_IFBLK, (major, minor)),
+            SpecialFile::Fifo => (S_IFIFO, (0, 0)),
+        };
+        self.insert_entry(path.as_ref(), file_type, attributes, Vec::new(), rdev);
+    }
+
+    fn insert_entry(
+        &mut self,
+        path: &Path,
+        file_type: u32,
+        attributes: EntryAttributes,
+        data: Vec<u8>,
+        (rdev_major, rdev_minor): (u32, u32),
+    ) {
+        let archive_path = normalize_path(path);
+        self.add_missing_parents(&archive_path);
+
+        debug!(
+            "Inserting into cpio: {} (mode: {:o}, size: {})",
+            archive_path,
+            file_type | attributes.mode,
+            data.len()
+        );
+
+        let entry = CpioEntry {
+            path: archive_path,
+            mode: file_type | (attributes.mode & 0o7777),
+            uid: attributes.uid,
+            gid: attributes.gid,
+            nlink: if file_type == S_IFDIR { 2 } else { 1 },
+            mtime: attributes.mtime,
+            data,
+            dev_major: 0,
+            dev_minor: 0,
+            rdev_major,
+            rdev_minor,
+            link: None,
+            host_owner: false,
+        };
+
+        match self.paths.get(&entry.path) {
+            Some(&index) => {
+                let replaced = std::mem::replace(&mut self.entries[index], entry);
+                // Another link to the replaced file now has to hold its data
+                if let Some(link) = replaced.link {
+                    match self.entries.iter_mut().find(|e| e.link == Some(link)) {
+                        Some(other) if other.data.is_empty() => other.data = replaced.data,
+                        Some(_) => {}
+                        // The next link added reads the data again
+                        None => {
+                            self.read_links.remove(&link);
+                        }
+                    }
+                }
+            }
+            None => self.push(entry),
+        }
+    }
+
+    fn add_missing_parents(&mut self, archive_path: &str) {
+        let mut parent = String::new();
+        let mut components: Vec<&str> = archive_path.split('/').collect();
+        components.pop();
+
+        for component in components {
+            if !parent.is_empty() {
+                parent.push('/');
+            }
+            parent.push_str(component);
+
+            // An existing entry may be a symlink (e.g. bin -> usr/bin), keep it
+            if self.paths.contains_key(&parent) {
+                continue;
+            }
+            self.push(CpioEntry {
+                path: parent.clone(),
+                mode: S_IFDIR | 0o755,
+                uid: 0,
+                gid: 0,
+                nlink: 2,
+                mtime: now(),
+                data: Vec::new(),
+                dev_major: 0,
+                dev_minor: 0,
+                rdev_major: 0,
+                rdev_minor: 0,
+                link: None,
+                host_owner: false,
+            });
+        }
+    }
+
     /// Write the archive to a file
+    ///
+    /// Hard links share an inode number and the file data goes with the last
+    /// of them only, as newc expects.
     pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
-        let mut ino = 1u32;
+        let mut entries: Vec<&CpioEntry> = self.entries.iter().collect();
+        if self.source_date_epoch.is_some() {
+            entries.sort_by(|a, b| a.path.cmp(&b.path));
+        }
+
+        let mut links: HashMap<(u64, u64), Vec<usize>> = HashMap::new();
+        for (index, entry) in entries.iter().enumerate() {
+            if let Some(link) = entry.link {
+                links.entry(link).or_default().push(index);
+            }
+        }
 
-        for entry in &self.entries {
-            self.write_entry(writer, entry, ino)?;
-            ino += 1;
+        let mut inodes: HashMap<(u64, u64), u32> = HashMap::new();
+        let mut next_ino = 1u32;
+        for (index, entry) in entries.iter().enumerate() {
+            let group = entry.link.and_then(|link| Some((link, links.get(&link)?)));
+            match group {
+                Some((link, group)) if group.len() > 1 => {
+                    let ino = *inodes.entry(link).or_insert_with(|| {
+                        next_ino += 1;
+                        next_ino - 1
+                    });
+                    let data = if group.last() == Some(&index) {
+                        group
+                            .iter()
+                            .map(|&i| entries[i].data.as_slice())
+                            .find(|data| !data.is_empty())
+                            .unwrap_or_default()
+                    } else {
+                        &[]
+                    };
+                    self.write_entry(writer, entry, ino, group.len() as u32, data)?;
+                }
+                _ => {
+                    let nlink = match self.source_date_epoch {
+                        Some(_) if entry.mode & S_IFMT == S_IFDIR => 2,
+                        Some(_) => 1,
+                        None => entry.nlink,
+                    };
+                    self.write_entry(writer, entry, next_ino, nlink, &entry.data)?;
+                    next_ino += 1;
+                }
+            }
         }
 
         // Write trailer
@@ -110,9 +476,26 @@ impl CpioArchive {
     }
 
     /// Write a single entry in newc format
-    fn write_entry<W: Write>(&self, writer: &mut W, entry: &CpioEntry, ino: u32) -> Result<()> {
+    fn write_entry<W: Write>(
+        &self,
+        writer: &mut W,
+        entry: &CpioEntry,
+        ino: u32,
+        nlink: u32,
+        data: &[u8],
+    ) -> Result<()> {
         let namesize = entry.path.len() + 1; // +1 for null terminator
-        let filesize = entry.data.len();
+        let filesize = data.len();
+        let (uid, gid) = match self.source_date_epoch {
+            // The build user differs between machines
+            Some(_) if entry.host_owner => (0, 0),
+            _ => (entry.uid, entry.gid),
+        };
+        let (uid, gid) = self.owners.owner(&entry.path, uid, gid);
+        let mtime = match self.source_date_epoch {
+            Some(epoch) => entry.mtime.min(epoch),
+            None => entry.mtime,
+        };
 
         // newc header format (110 bytes of ASCII hex)
         let header = format!(
@@ -120,10 +503,10 @@ impl CpioArchive {
             "070701",         // magic
             ino,              // inode
             entry.mode,       // mode
-            entry.uid,        // uid
-            entry.gid,        // gid
-            entry.nlink,      // nlink
-            entry.mtime,      // mtime
+            uid,              // uid
+            gid,              // gid
+            nlink,            // nlink
+            mtime,            // mtime
             filesize,         // filesize
             entry.dev_major,  // dev major
             entry.dev_minor,  // dev minor
@@ -142,7 +525,7 @@ impl CpioArchive {
         let padding = (4 - (header_plus_name % 4)) % 4;
         writer.write_all(&vec![0u8; padding])?;
 
-        writer.write_all(&entry.data)?;
+        writer.write_all(data)?;
 
         // Pad data to 4-byte boundary
         let data_padding = (4 - (filesize % 4)) % 4;
@@ -183,6 +566,39 @@ impl CpioArchive {
     }
 }
 
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFIFO: u32 = 0o010000;
+
+/// Major and minor numbers of a Linux `dev_t`
+fn split_dev(dev: u64) -> (u32, u32) {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    (major as u32, minor as u32)
+}
+
+/// Archive path for `path`: relative to the archive root, without `.` components
+pub(super) fn normalize_path(path: &Path) -> String {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0)
+}
+
 impl Default for CpioArchive {
     fn default() -> Self {
         Self::new()
@@ -212,6 +628,360 @@ mod tests {
         assert_eq!(archive.len(), 1);
     }
 
+    #[test]
+    fn test_archive_uses_recorded_ownership() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::create_dir(temp_dir.path().join("etc")).unwrap();
+        fs::write(temp_dir.path().join("etc/nginx.conf"), b"conf").unwrap();
+
+        let mut overlay = MetadataOverlay::new();
+        overlay.insert(
+            "etc/nginx.conf",
+            FileMetadata {
+                uid: 101,
+                gid: 102,
+                mode: 0o4640,
+            },
+        );
+
+        let archive = CpioArchive::from_directory_with_metadata(temp_dir.path(), &overlay).unwrap();
+        let conf = archive
+            .entries
+            .iter()
+            .find(|e| e.path == "etc/nginx.conf")
+            .unwrap();
+        assert_eq!((conf.uid, conf.gid), (101, 102));
+        assert_eq!(conf.mode, S_IFREG | 0o4640);
+
+        // Paths the overlay does not know keep their host metadata
+        let etc = archive.entries.iter().find(|e| e.path == "etc").unwrap();
+        assert_eq!(etc.mode & S_IFMT, S_IFDIR);
+    }
+
+    #[test]
+    fn test_special_files() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::create_dir(temp_dir.path().join("dev")).unwrap();
+        fs::write(temp_dir.path().join("dev/null"), b"").unwrap();
+
+        let mut overlay = MetadataOverlay::new();
+        let metadata = FileMetadata {
+            uid: 0,
+            gid: 0,
+            mode: 0o666,
+        };
+        overlay.insert("dev/null", metadata);
+        overlay.set_special("dev/null", SpecialFile::CharDevice { major: 1, minor: 3 });
+
+        let mut archive =
+            CpioArchive::from_directory_with_metadata(temp_dir.path(), &overlay).unwrap();
+        archive.add_special(
+            "/run/initctl",
+            SpecialFile::Fifo,
+            EntryAttributes::new(0o666),
+        );
+
+        let null = archive
+            .entries
+            .iter()
+            .find(|e| e.path == "dev/null")
+            .unwrap();
+        assert_eq!(null.mode, S_IFCHR | 0o666);
+        assert_eq!((null.rdev_major, null.rdev_minor), (1, 3));
+        assert!(null.data.is_empty());
+
+        let fifo = archive
+            .entries
+            .iter()
+            .find(|e| e.path == "run/initctl")
+            .unwrap();
+        assert_eq!(fifo.mode, S_IFIFO | 0o666);
+        assert!(archive.contains(Path::new("run")));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut archive = CpioArchive::new();
+        let attributes = EntryAttributes::new(0o755);
+        archive.add_symlink("bin", "usr/bin", attributes);
+        archive.add_file("usr/bin/busybox", "", attributes);
+        archive.add_symlink("usr/bin/sh", "busybox", attributes);
+        archive.add_symlink("sbin/setfattr", "../bin/missing", attributes);
+        archive.add_symlink("loop", "/loop", attributes);
+
+        assert_eq!(
+            archive.resolve("/bin/sh").as_deref(),
+            Some("usr/bin/busybox")
+        );
+        assert_eq!(archive.resolve("usr/bin").as_deref(), Some("usr/bin"));
+        assert_eq!(archive.resolve("/sbin/setfattr"), None);
+        assert_eq!(archive.resolve("bin/setfattr"), None);
+        assert_eq!(archive.resolve("loop"), None);
+    }
+
+    #[test]
+    fn test_synthetic_entries() {
+        let mut archive = CpioArchive::new();
+        archive.add_directory(
+            "/var/lib/agent",
+            EntryAttributes::new(0o700)
+                .owner(1000, 1000)
+                .mtime(1_700_000_000),
+        );
+        archive.add_symlink("/bin", "usr/bin", EntryAttributes::new(0o777));
+
+        let agent = archive
+            .entries
+            .iter()
+            .find(|e| e.path == "var/lib/agent")
+            .unwrap();
+        assert_eq!(agent.mode, S_IFDIR | 0o700);
+        assert_eq!(
+            (agent.uid, agent.gid, agent.mtime),
+            (1000, 1000, 1_700_000_000)
+        );
+
+        let bin = archive.entries.iter().find(|e| e.path == "bin").unwrap();
+        assert_eq!(bin.mode, S_IFLNK | 0o777);
+        assert_eq!(bin.data, b"usr/bin");
+        assert!(archive.contains("var") && archive.contains("/var/lib"));
+    }
+
+    /// (path, ino, nlink, data) of each entry of a written archive
+    fn written_entries(archive: &CpioArchive) -> Vec<(String, u32, u32, Vec<u8>)> {
+        written_headers(archive)
+            .into_iter()
+            .map(|(path, fields, data)| (path, fields[0], fields[4], data))
+            .collect()
+    }
+
+    /// Path, the 13 header fields after the magic and data of each written entry
+    fn written_headers(archive: &CpioArchive) -> Vec<(String, Vec<u32>, Vec<u8>)> {
+        let mut output = Vec::new();
+        archive.write_to(&mut output).unwrap();
+
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        loop {
+            let fields: Vec<u32> = (0..13)
+                .map(|i| {
+                    let at = offset + 6 + i * 8;
+                    let hex = std::str::from_utf8(&output[at..at + 8]).unwrap();
+                    u32::from_str_radix(hex, 16).unwrap()
+                })
+                .collect();
+            let (filesize, namesize) = (fields[6] as usize, fields[11] as usize);
+            let name_start = offset + 110;
+            let path =
+                String::from_utf8(output[name_start..name_start + namesize - 1].to_vec()).unwrap();
+            if path == "TRAILER!!!" {
+                return entries;
+            }
+            let data_start = (name_start + namesize).next_multiple_of(4);
+            entries.push((
+                path,
+                fields,
+                output[data_start..data_start + filesize].to_vec(),
+            ));
+            offset = (data_start + filesize).next_multiple_of(4);
+        }
+    }
+
+    #[test]
+    fn test_hard_links_share_inode_and_data() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::write(root.join("git"), b"binary").unwrap();
+        fs::hard_link(root.join("git"), root.join("git-upload-pack")).unwrap();
+        fs::hard_link(root.join("git"), root.join("git-receive-pack")).unwrap();
+        fs::write(root.join("other"), b"other").unwrap();
+
+        let mut archive = CpioArchive::from_directory(root).unwrap();
+        let entries = written_entries(&archive);
+
+        let links: Vec<_> = entries.iter().filter(|e| e.0.starts_with("git")).collect();
+        assert_eq!(links.len(), 3);
+        assert!(links.iter().all(|e| e.1 == links[0].1 && e.2 == 3));
+        // Only the last link carries the data
+        assert!(links[..2].iter().all(|e| e.3.is_empty()));
+        assert_eq!(links[2].3, b"binary");
+
+        let other = entries.iter().find(|e| e.0 == "other").unwrap();
+        assert_eq!(other.2, 1);
+        assert_ne!(other.1, links[0].1);
+
+        // Replacing links leaves the others a valid group
+        let first = links[0].0.clone();
+        archive.add_file(&first, b"new".to_vec(), EntryAttributes::new(0o755));
+        let entries = written_entries(&archive);
+        let links: Vec<_> = entries
+            .iter()
+            .filter(|e| e.0.starts_with("git") && e.0 != first)
+            .collect();
+        assert!(links.iter().all(|e| e.2 == 2));
+        assert_eq!(links[1].3, b"binary");
+
+        // A link added after the one holding the data was replaced reads it again
+        let mut archive = CpioArchive::new();
+        archive
+            .add_path(&root.join("git"), "git", None, None)
+            .unwrap();
+        archive.add_file("git", b"new".to_vec(), EntryAttributes::new(0o755));
+        archive
+            .add_path(&root.join("git-upload-pack"), "git-upload-pack", None, None)
+            .unwrap();
+        let entries = written_entries(&archive);
+        assert_eq!(entries[1].0, "git-upload-pack");
+        assert_eq!(entries[1].3, b"binary");
+    }
+
+    #[test]
+    fn test_reproducible_output() {
+        let build = |paths: &[&str]| {
+            let mut archive = CpioArchive::new();
+            for path in paths {
+                archive.add_file(*path, path.as_bytes(), EntryAttributes::new(0o644));
+            }
+            archive.add_directory("etc", EntryAttributes::new(0o755).mtime(1_000_000));
+            archive.set_reproducible(1_700_000_000);
+            let mut output = Vec::new();
+            archive.write_to(&mut output).unwrap();
+            output
+        };
+
+        let first = build(&["usr/bin/env", "etc/hostname", "init"]);
+        let second = build(&["init", "etc/hostname", "usr/bin/env"]);
+        assert_eq!(first, second);
+
+        let mut archive = CpioArchive::new();
+        archive.add_file("b", b"b".to_vec(), EntryAttributes::new(0o644));
+        archive.add_directory("a", EntryAttributes::new(0o755).mtime(1_000_000));
+        archive.add_file("a/x", b"x".to_vec(), EntryAttributes::new(0o644));
+        archive.set_reproducible(1_700_000_000);
+        let mut output = Vec::new();
+        archive.write_to(&mut output).unwrap();
+
+        let paths: Vec<String> = written_entries(&archive).into_iter().map(|e| e.0).collect();
+        assert_eq!(paths, ["a", "a/x", "b"]);
+        let mtime = |at: usize| {
+            u32::from_str_radix(std::str::from_utf8(&output[at + 46..at + 54]).unwrap(), 16)
+                .unwrap()
+        };
+        // Older timestamps are kept, newer ones clamped
+        assert_eq!(mtime(0), 1_000_000);
+        let second_header = (110 + 2usize).next_multiple_of(4);
+        assert_eq!(mtime(second_header), 1_700_000_000);
+    }
+
+    #[test]
+    fn test_reproducible_host_attributes() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("etc/conf.d")).unwrap();
+        fs::set_permissions(root.join("etc"), fs::Permissions::from_mode(0o700)).unwrap();
+        fs::write(root.join("etc/secret"), b"x").unwrap();
+        fs::set_permissions(root.join("etc/secret"), fs::Permissions::from_mode(0o600)).unwrap();
+        fs::write(root.join("run"), b"x").unwrap();
+        fs::set_permissions(root.join("run"), fs::Permissions::from_mode(0o750)).unwrap();
+
+        let mut overlay = MetadataOverlay::new();
+        overlay.insert(
+            "run",
+            FileMetadata {
+                uid: 1000,
+                gid: 1000,
+                mode: 0o4750,
+            },
+        );
+        let mut archive = CpioArchive::from_directory_with_metadata(root, &overlay).unwrap();
+        archive.set_reproducible(0);
+        let conf_d_mode = 0o040000 | fs::metadata(root.join("etc/conf.d")).unwrap().mode() & 0o7777;
+
+        // (path, mode, uid, gid, nlink)
+        let written: Vec<(String, u32, u32, u32, u32)> = written_headers(&archive)
+            .into_iter()
+            .map(|(path, f, _)| (path, f[1], f[2], f[3], f[4]))
+            .collect();
+        assert_eq!(
+            written,
+            [
+                // Host permissions are kept, owners and link counts are not
+                ("etc".to_string(), 0o040700, 0, 0, 2),
+                ("etc/conf.d".to_string(), conf_d_mode, 0, 0, 2),
+                ("etc/secret".to_string(), 0o100600, 0, 0, 1),
+                // Recorded attributes are kept
+                ("run".to_string(), 0o104750, 1000, 1000, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reproducible_owner_rules_win() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("var/lib/agent")).unwrap();
+        fs::write(root.join("var/lib/agent/state"), b"{}").unwrap();
+
+        let mut archive = CpioArchive::from_directory(root).unwrap();
+        archive.set_reproducible(0);
+        archive.set_owners(OwnerMap::new().path("/var/lib/agent", 1000, 100));
+
+        let owners: Vec<(String, u32, u32)> = written_headers(&archive)
+            .into_iter()
+            .map(|(path, f, _)| (path, f[2], f[3]))
+            .collect();
+        assert_eq!(
+            owners,
+            [
+                ("var".to_string(), 0, 0),
+                ("var/lib".to_string(), 0, 0),
+                ("var/lib/agent".to_string(), 1000, 100),
+                ("var/lib/agent/state".to_string(), 1000, 100),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_dev() {
+        assert_eq!(split_dev(0x0501), (5, 1));
+        // Large minors spill into the upper bits
+        assert_eq!(split_dev(0x0100_0803), (8, 0x1003));
+    }
+
+    #[test]
+    fn test_owners_applied_when_written() {
+        let mut archive = CpioArchive::new();
+        archive.add_file(
+            "/usr/bin/agent",
+            b"bin".to_vec(),
+            EntryAttributes::new(0o755),
+        );
+        archive.set_owners(OwnerMap::new().squash(0, 0).path("/usr/bin", 1000, 100));
+
+        let mut output = Vec::new();
+        archive.write_to(&mut output).unwrap();
+
+        // Headers in order: usr, usr/bin, usr/bin/agent
+        let owner = |index: usize| {
+            let header = &output[..];
+            let start = header
+                .windows(6)
+                .enumerate()
+                .filter(|(_, w)| *w == b"070701")
+                .nth(index)
+                .unwrap()
+                .0;
+            let field = |at: usize| {
+                let hex = std::str::from_utf8(&header[start + at..start + at + 8]).unwrap();
+                u32::from_str_radix(hex, 16).unwrap()
+            };
+            (field(22), field(30))
+        };
+        assert_eq!(owner(0), (0, 0));
+        assert_eq!(owner(1), (1000, 100));
+        assert_eq!(owner(2), (1000, 100));
+    }
+
     #[test]
     fn test_cpio_header_magic() {
         let temp_dir = TempDir::new().unwrap();
@@ -267,6 +1037,35 @@ mod tests {
         assert_eq!(archive.len(), 2);
     }
 
+    #[test]
+    fn test_add_file_replaces_and_adds_parents() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(temp_dir.path().join("init"), b"old").unwrap();
+        fs::create_dir(temp_dir.path().join("usr")).unwrap();
+
+        let mut archive = CpioArchive::from_directory(temp_dir.path()).unwrap();
+        archive.add_file("/init", b"new".to_vec(), EntryAttributes::new(0o755));
+        archive.add_file(
+            "/usr/local/bin/agent",
+            b"bin".to_vec(),
+            EntryAttributes::new(0o755),
+        );
+
+        let paths: Vec<&str> = archive.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths.iter().filter(|p| **p == "init").count(), 1);
+        assert_eq!(paths.iter().filter(|p| **p == "usr").count(), 1);
+        let local = paths.iter().position(|p| *p == "usr/local").unwrap();
+        let agent = paths
+            .iter()
+            .position(|p| *p == "usr/local/bin/agent")
+            .unwrap();
+        assert!(local < agent, "parents must precede their children");
+
+        let init = archive.entries.iter().find(|e| e.path == "init").unwrap();
+        assert_eq!(init.data, b"new");
+        assert_eq!(init.mode, 0o100755);
+    }
+
     #[test]
     fn test_output_alignment() {
         let temp_dir = TempDir::new().unwrap();
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Ownership and permission bits of a file as recorded in its image layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub uid: u32,
+    pub gid: u32,
+    /// Permission bits, including setuid/setgid/sticky (`0o7777`)
+    pub mode: u32,
+}
+
+/// Extended attribute of a file, from a `SCHILY.xattr.<name>` PAX record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Xattr {
+    /// Full name, namespace included (`security.capability`)
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+/// Device node or FIFO from a layer
+///
+/// Creating device nodes needs root, so the rootfs only holds an empty
+/// placeholder file and the archive gets the node from here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecialFile {
+    CharDevice { major: u32, minor: u32 },
+    BlockDevice { major: u32, minor: u32 },
+    Fifo,
+}
+
+/// Metadata from layer tar headers, keyed by path relative to the rootfs
+///
+/// Extraction runs unprivileged, so the files on disk belong to the builder
+/// user. The overlay keeps what the image said so the archive can use it.
+#[derive(Debug, Clone, Default)]
+pub struct MetadataOverlay {
+    entries: HashMap<PathBuf, FileMetadata>,
+    xattrs: HashMap<PathBuf, Vec<Xattr>>,
+    special: HashMap<PathBuf, SpecialFile>,
+}
+
+impl MetadataOverlay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record `metadata` for `path`, replacing what a lower layer said
+    /// (extended attributes and special file type included)
+    pub fn insert(&mut self, path: impl Into<PathBuf>, metadata: FileMetadata) {
+        let path = path.into();
+        self.xattrs.remove(&path);
+        self.special.remove(&path);
+        self.entries.insert(path, metadata);
+    }
+
+    /// Record that the placeholder at `path` stands for `special`
+    pub fn set_special(&mut self, path: impl Into<PathBuf>, special: SpecialFile) {
+        self.special.insert(path.into(), special);
+    }
+
+    pub fn special(&self, path: &Path) -> Option<SpecialFile> {
+        self.special.get(path).copied()
+    }
+
+    /// Record the extended attributes of `path`
+    pub fn set_xattrs(&mut self, path: impl Into<PathBuf>, xattrs: Vec<Xattr>) {
+        let path = path.into();
+        if xattrs.is_empty() {
+            self.xattrs.remove(&path);
+        } else {
+            self.xattrs.insert(path, xattrs);
+        }
+    }
+
+    /// Paths with extended attributes, sorted by path
+    pub fn xattrs(&self) -> Vec<(&Path, &[Xattr])> {
+        let mut xattrs: Vec<_> = self
+            .xattrs
+            .iter()
+            .map(|(path, xattrs)| (path.as_path(), xattrs.as_slice()))
+            .collect();
+        xattrs.sort_by_key(|(path, _)| *path);
+        xattrs
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&FileMetadata> {
+        self.entries.get(path)
+    }
+
+    /// Forget `path` and everything below it (whiteouts)
+    pub fn remove_tree(&mut self, path: &Path) {
+        self.entries
+            .retain(|recorded, _| !recorded.starts_with(path));
+        self.xattrs
+            .retain(|recorded, _| !recorded.starts_with(path));
+        self.special
+            .retain(|recorded, _| !recorded.starts_with(path));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_tree() {
+        let nginx = FileMetadata {
+            uid: 101,
+            gid: 101,
+            mode: 0o755,
+        };
+        let mut overlay = MetadataOverlay::new();
+        overlay.insert("var/cache/nginx", nginx);
+        overlay.insert("var/cache/nginx/client_temp", nginx);
+        overlay.insert("var/cache/nginx-other", nginx);
+        overlay.set_xattrs(
+            "var/cache/nginx/client_temp",
+            vec![Xattr {
+                name: "user.origin".to_string(),
+                value: b"nginx".to_vec(),
+            }],
+        );
+
+        overlay.remove_tree(Path::new("var/cache/nginx"));
+
+        assert_eq!(overlay.len(), 1);
+        assert!(overlay.xattrs().is_empty());
+        assert_eq!(
+            overlay.get(Path::new("var/cache/nginx-other")),
+            Some(&nginx)
+        );
+    }
+}
@@ -1,5 +1,11 @@
 mod compress;
 mod cpio;
+mod metadata;
+mod owner;
+mod xattr;
 
 pub use compress::{compress_archive, Compression};
-pub use cpio::CpioArchive;
+pub use cpio::{CpioArchive, EntryAttributes};
+pub use metadata::{FileMetadata, MetadataOverlay, SpecialFile, Xattr};
+pub use owner::OwnerMap;
+pub use xattr::{restore_init, WRAPPED_INIT};
@@ -0,0 +1,82 @@
+use std::path::Path;
+
+use super::cpio::normalize_path;
+
+/// Ownership rewrites applied to archive entries as they are written
+///
+/// A path rule covers the path and everything below it, the most specific rule
+/// wins. Entries no rule covers get the squashed owner, if any, and otherwise
+/// keep the owner they were added with.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OwnerMap {
+    squash: Option<(u32, u32)>,
+    paths: Vec<(String, u32, u32)>,
+}
+
+impl OwnerMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give every entry `uid`/`gid`
+    pub fn squash(mut self, uid: u32, gid: u32) -> Self {
+        self.squash = Some((uid, gid));
+        self
+    }
+
+    /// Give `path` and everything below it `uid`/`gid`
+    pub fn path(mut self, path: impl AsRef<Path>, uid: u32, gid: u32) -> Self {
+        let path = normalize_path(path.as_ref());
+        self.paths.retain(|(existing, _, _)| *existing != path);
+        self.paths.push((path, uid, gid));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.squash.is_none() && self.paths.is_empty()
+    }
+
+    /// Owner of the entry at `archive_path`, currently owned by `uid`/`gid`
+    pub(crate) fn owner(&self, archive_path: &str, uid: u32, gid: u32) -> (u32, u32) {
+        self.paths
+            .iter()
+            .filter(|(rule, _, _)| covers(rule, archive_path))
+            .max_by_key(|(rule, _, _)| rule.len())
+            .map(|(_, uid, gid)| (*uid, *gid))
+            .or(self.squash)
+            .unwrap_or((uid, gid))
+    }
+}
+
+fn covers(rule: &str, archive_path: &str) -> bool {
+    rule.is_empty()
+        || archive_path
+            .strip_prefix(rule)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_most_specific_rule_wins() {
+        let owners = OwnerMap::new()
+            .squash(0, 0)
+            .path("/var/lib/agent", 1000, 1000)
+            .path("/var/lib/agent/secrets", 1001, 1001);
+
+        assert_eq!(owners.owner("etc/passwd", 101, 101), (0, 0));
+        assert_eq!(owners.owner("var/lib/agent", 0, 0), (1000, 1000));
+        assert_eq!(owners.owner("var/lib/agent/state", 0, 0), (1000, 1000));
+        assert_eq!(
+            owners.owner("var/lib/agent/secrets/key", 0, 0),
+            (1001, 1001)
+        );
+        assert_eq!(owners.owner("var/lib/agent-old", 5, 5), (0, 0));
+
+        // Without a squash, uncovered entries keep their owner
+        let owners = OwnerMap::new().path("home/app", 1000, 1000);
+        assert_eq!(owners.owner("home", 5, 6), (5, 6));
+    }
+}
@@ -0,0 +1,83 @@
+use std::fmt::Write;
+use std::path::Path;
+
+use super::metadata::Xattr;
+
+/// Preferred path of the real init when `/init` restores extended attributes first
+pub const WRAPPED_INIT: &str = "/.init";
+
+/// `/init` script that restores `xattrs` then runs the real init at `wrapped_init`
+///
+/// newc archives have no room for extended attributes, so they are set at boot
+/// with `setfattr` (from the `attr` package, busybox's cannot decode binary
+/// values). Files whose attribute fails to apply keep booting without it.
+pub fn restore_init(xattrs: &[(&Path, &[Xattr])], wrapped_init: &str) -> Vec<u8> {
+    let mut script = String::from(
+        "#!/bin/sh\n\
+         # Generated by initramfs-builder: restore the extended attributes\n\
+         # recorded in the image layers, which cpio archives cannot carry.\n\
+         if command -v setfattr >/dev/null 2>&1; then\n",
+    );
+
+    for (path, xattrs) in xattrs {
+        let path = Path::new("/").join(path);
+        for xattr in *xattrs {
+            let value: String = xattr.value.iter().map(|b| format!("{:02x}", b)).collect();
+            let _ = writeln!(
+                script,
+                "    setfattr -h -n {} -v 0x{} {}",
+                shell_quote(&xattr.name),
+                value,
+                shell_quote(&path.to_string_lossy())
+            );
+        }
+    }
+
+    let _ = write!(
+        script,
+        "else\n    \
+         echo \"init: setfattr not found, extended attributes not restored\" >&2\n\
+         fi\n\
+         exec {} \"$@\"\n",
+        shell_quote(wrapped_init)
+    );
+    script.into_bytes()
+}
+
+/// `value` as a single-quoted shell word
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restore_init() {
+        let capability = [Xattr {
+            name: "security.capability".to_string(),
+            value: vec![0x01, 0x00, 0x00, 0x02, 0x00, 0x20],
+        }];
+        let label = [Xattr {
+            name: "security.selinux".to_string(),
+            value: b"system_u:object_r:bin_t:s0\0".to_vec(),
+        }];
+        let script = restore_init(
+            &[
+                (Path::new("bin/ping"), &capability),
+                (Path::new("opt/it's here"), &label),
+            ],
+            WRAPPED_INIT,
+        );
+        let script = String::from_utf8(script).unwrap();
+
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script
+            .contains("    setfattr -h -n 'security.capability' -v 0x010000020020 '/bin/ping'\n"));
+        assert!(script.contains(
+            "-v 0x73797374656d5f753a6f626a6563745f723a62696e5f743a733000 '/opt/it'\\''s here'\n"
+        ));
+        assert!(script.ends_with("fi\nexec '/.init' \"$@\"\n"));
+    }
+}
@@ -27,16 +27,22 @@ pub mod initramfs;
 pub mod registry;
 
 pub use error::{BuilderError, Result};
-pub use initramfs::{compress_archive, Compression};
-pub use registry::{PullOptions, RegistryAuth, RegistryClient};
+pub use image::{ImageSource, RejectedEntry};
+pub use initramfs::{
+    compress_archive, Compression, CpioArchive, EntryAttributes, OwnerMap, SpecialFile,
+};
+pub use registry::{
+    BlobCache, DockerConfig, PullOptions, RegistryAuth, RegistryClient, RegistryConfig, RetryPolicy,
+};
 
 use anyhow::Context;
-use image::RootfsBuilder;
-use initramfs::CpioArchive;
+use image::{open_source, RootfsBuilder};
+use initramfs::{restore_init, WRAPPED_INIT};
+use std::collections::HashSet;
 use std::fs;
 use std::os::unix::fs::PermissionsExt;
-use std::path::{Path, PathBuf};
-use tracing::info;
+use std::path::{Component, Path, PathBuf};
+use tracing::{info, warn};
 
 #[derive(Debug, Clone)]
 pub struct InjectFile {
@@ -62,26 +68,50 @@ impl InjectFile {
 
 pub struct InitramfsBuilder {
     image: Option<String>,
+    source: Option<Box<dyn ImageSource>>,
+    rootfs_dir: Option<PathBuf>,
     compression: Compression,
     exclude_patterns: Vec<String>,
-    platform_os: String,
-    platform_arch: String,
+    pull_options: PullOptions,
     auth: RegistryAuth,
+    registry_config: RegistryConfig,
+    blob_cache: Option<BlobCache>,
     inject_files: Vec<InjectFile>,
     init_script: Option<PathBuf>,
+    owners: OwnerMap,
+    reproducible: bool,
+    source_date_epoch: Option<u64>,
 }
 
 impl InitramfsBuilder {
     pub fn new() -> Self {
         Self {
             image: None,
+            source: None,
+            rootfs_dir: None,
             compression: Compression::default(),
             exclude_patterns: Vec::new(),
-            platform_os: "linux".to_string(),
-            platform_arch: "amd64".to_string(),
+            pull_options: PullOptions::default(),
             auth: RegistryAuth::default(),
+            registry_config: RegistryConfig::default(),
+            blob_cache: None,
             inject_files: Vec::new(),
             init_script: None,
+            owners: OwnerMap::new(),
+            reproducible: false,
+            source_date_epoch: None,
+        }
+    }
+
+    /// Build from a prepared root filesystem directory instead of an image
+    ///
+    /// Nothing is pulled or extracted: injected files and `/init` are added to
+    /// the archive on top of the directory contents, which are left untouched.
+    /// Exclude patterns only apply to image layers, building fails when any are set.
+    pub fn from_directory(path: impl Into<PathBuf>) -> Self {
+        Self {
+            rootfs_dir: Some(path.into()),
+            ..Self::new()
         }
     }
 
@@ -90,6 +120,12 @@ impl InitramfsBuilder {
         self
     }
 
+    /// Read the image from a custom [`ImageSource`] instead of `image`
+    pub fn source(mut self, source: impl ImageSource + 'static) -> Self {
+        self.source = Some(Box::new(source));
+        self
+    }
+
     pub fn compression(mut self, compression: Compression) -> Self {
         self.compression = compression;
         self
@@ -102,8 +138,26 @@ impl InitramfsBuilder {
     }
 
     pub fn platform(mut self, os: &str, arch: &str) -> Self {
-        self.platform_os = os.to_string();
-        self.platform_arch = arch.to_string();
+        self.pull_options.platform_os = os.to_string();
+        self.pull_options.platform_arch = arch.to_string();
+        self
+    }
+
+    /// Download up to `count` layers at the same time (default 3)
+    pub fn max_concurrent_downloads(mut self, count: usize) -> Self {
+        self.pull_options = self.pull_options.concurrent_downloads(count);
+        self
+    }
+
+    /// Pull `registry` images through `mirror` first (can be repeated, tried in order)
+    pub fn registry_mirror(mut self, registry: &str, mirror: &str) -> Self {
+        self.pull_options = self.pull_options.mirror(registry, mirror);
+        self
+    }
+
+    /// Retry transient registry failures (5xx, 429, dropped connections) according to `policy`
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.pull_options = self.pull_options.retry_policy(policy);
         self
     }
 
@@ -113,6 +167,26 @@ impl InitramfsBuilder {
         self
     }
 
+    /// Reach a registry (`host:port`) over plain HTTP
+    pub fn insecure_registry(mut self, registry: &str) -> Self {
+        self.registry_config
+            .insecure_registries
+            .push(registry.to_string());
+        self
+    }
+
+    /// Trust an extra CA certificate (PEM file) when talking to registries
+    pub fn ca_certificate(mut self, path: impl Into<PathBuf>) -> Self {
+        self.registry_config.ca_certificates.push(path.into());
+        self
+    }
+
+    /// Keep downloaded layers in `cache` and reuse them on later builds
+    pub fn blob_cache(mut self, cache: BlobCache) -> Self {
+        self.blob_cache = Some(cache);
+        self
+    }
+
     /// Inject a file into the initramfs
     ///
     /// # Arguments
@@ -137,71 +211,196 @@ impl InitramfsBuilder {
         self
     }
 
+    /// Make every archive entry owned by `uid`/`gid` (e.g. `0, 0` for root)
+    ///
+    /// Paths given to [`path_owner`](Self::path_owner) keep their own owner.
+    pub fn owner_override(mut self, uid: u32, gid: u32) -> Self {
+        self.owners = self.owners.squash(uid, gid);
+        self
+    }
+
+    /// Make `path` and everything below it owned by `uid`/`gid` in the archive
+    pub fn path_owner(mut self, path: impl AsRef<Path>, uid: u32, gid: u32) -> Self {
+        self.owners = self.owners.path(path, uid, gid);
+        self
+    }
+
+    /// Produce byte-for-byte identical output for identical inputs
+    ///
+    /// Entries are sorted and timestamps clamped to the source date epoch: the one
+    /// given to [`source_date_epoch`](Self::source_date_epoch), else
+    /// `$SOURCE_DATE_EPOCH`, else the image creation date (0 without an image).
+    pub fn reproducible(mut self) -> Self {
+        self.reproducible = true;
+        self
+    }
+
+    /// Clamp timestamps to `epoch` (seconds since the Unix epoch)
+    ///
+    /// Implies [`reproducible`](Self::reproducible).
+    pub fn source_date_epoch(mut self, epoch: u64) -> Self {
+        self.reproducible = true;
+        self.source_date_epoch = Some(epoch);
+        self
+    }
+
     /// Build the initramfs and write it to the output path
     pub async fn build<P: AsRef<Path>>(self, output: P) -> anyhow::Result<BuildResult> {
-        let image = self.image.as_ref().context("No image specified")?;
+        // Keeps the extracted image alive until the archive has been written
+        let mut rootfs_builder = None;
 
-        info!("Building initramfs from {}", image);
+        let rootfs_path = if let Some(dir) = &self.rootfs_dir {
+            info!("Building initramfs from directory {:?}", dir);
+            if !dir.is_dir() {
+                anyhow::bail!("{:?} is not a directory", dir);
+            }
+            if !self.exclude_patterns.is_empty() {
+                anyhow::bail!(
+                    "Exclude patterns cannot be used when building from directory {:?}",
+                    dir
+                );
+            }
+            dir.clone()
+        } else {
+            let source = match self.source {
+                Some(source) => {
+                    info!("Building initramfs from custom image source");
+                    source
+                }
+                None => {
+                    let image = self.image.as_ref().context("No image specified")?;
+                    info!("Building initramfs from {}", image);
+
+                    let mut client = RegistryClient::with_config(self.auth, self.registry_config)?;
+                    if let Some(cache) = self.blob_cache {
+                        client = client.with_cache(cache);
+                    }
+                    open_source(image, client)?
+                }
+            };
 
-        let client = RegistryClient::new(self.auth);
-        let exclude_refs: Vec<&str> = self.exclude_patterns.iter().map(|s| s.as_str()).collect();
+            let exclude_refs: Vec<&str> =
+                self.exclude_patterns.iter().map(|s| s.as_str()).collect();
 
-        let mut rootfs_builder = RootfsBuilder::new(client)
-            .platform(&self.platform_os, &self.platform_arch)
-            .exclude(&exclude_refs);
+            rootfs_builder
+                .insert(
+                    RootfsBuilder::new(source)
+                        .pull_options(self.pull_options)
+                        .exclude(&exclude_refs),
+                )
+                .build()
+                .await?
+        };
 
-        let rootfs_path = rootfs_builder.build(image).await?;
+        info!("Creating CPIO archive from {:?}", rootfs_path);
 
-        for inject in &self.inject_files {
-            let dest_path = if inject.dest.is_absolute() {
-                rootfs_path.join(inject.dest.strip_prefix("/").unwrap_or(&inject.dest))
-            } else {
-                rootfs_path.join(&inject.dest)
-            };
+        // Files extracted from an image carry the ownership recorded in its layers
+        let mut archive = match &rootfs_builder {
+            Some(builder) => {
+                CpioArchive::from_directory_with_metadata(&rootfs_path, builder.metadata())?
+            }
+            None => CpioArchive::from_directory(&rootfs_path)?,
+        };
 
-            if let Some(parent) = dest_path.parent() {
-                fs::create_dir_all(parent)?;
+        // The kernel opens /dev/console before anything can mount devtmpfs
+        for (path, device, mode) in EARLY_DEVICES {
+            if !archive.contains(path) {
+                archive.add_special(path, device, EntryAttributes::new(mode));
             }
+        }
 
+        for inject in &self.inject_files {
             info!("Injecting {:?} -> {:?}", inject.src, inject.dest);
-            fs::copy(&inject.src, &dest_path)
+            let data = fs::read(&inject.src)
                 .with_context(|| format!("Failed to inject {:?}", inject.src))?;
-
-            if inject.executable {
-                let mut perms = fs::metadata(&dest_path)?.permissions();
-                perms.set_mode(0o755);
-                fs::set_permissions(&dest_path, perms)?;
-            }
+            let mode = if inject.executable {
+                0o755
+            } else {
+                fs::metadata(&inject.src)?.permissions().mode()
+            };
+            archive.add_file(&inject.dest, data, EntryAttributes::new(mode));
         }
 
-        let init_dest = rootfs_path.join("init");
-        if let Some(init_src) = &self.init_script {
+        let init = if let Some(init_src) = &self.init_script {
             info!("Setting init script from {:?}", init_src);
-            fs::copy(init_src, &init_dest)
-                .with_context(|| format!("Failed to copy init script from {:?}", init_src))?;
+            fs::read(init_src)
+                .with_context(|| format!("Failed to read init script from {:?}", init_src))?
         } else {
             info!("Generating default init script");
-            let default_init = r#"#!/bin/sh
-mount -t proc proc /proc 2>/dev/null
-mount -t sysfs sysfs /sys 2>/dev/null
-mount -t devtmpfs devtmpfs /dev 2>/dev/null
-
-for cmd in /docker-entrypoint.sh /entrypoint.sh /usr/bin/entrypoint.sh; do
-    [ -x "$cmd" ] && exec "$cmd"
-done
-
-exec /bin/sh
-"#;
-            fs::write(&init_dest, default_init)?;
+            DEFAULT_INIT.as_bytes().to_vec()
+        };
+
+        // The archive cannot carry extended attributes, /init sets them at boot.
+        // Injected files and /init replaced what the image had at their path,
+        // which is where the archive's symlinks lead.
+        let replaced: HashSet<PathBuf> = self
+            .inject_files
+            .iter()
+            .map(|inject| {
+                archive
+                    .resolve(&inject.dest)
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| archive_relative(&inject.dest))
+            })
+            .chain([PathBuf::from("init")])
+            .collect();
+        let xattrs: Vec<_> = rootfs_builder
+            .as_ref()
+            .map(|builder| builder.metadata().xattrs())
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(path, _)| !replaced.contains(*path))
+            .collect();
+        let mut restored_xattrs = 0;
+        let mut dropped_xattrs = Vec::new();
+        if xattrs.is_empty() {
+            archive.add_file("init", init, EntryAttributes::new(0o755));
+        } else if can_restore_xattrs(&archive) {
+            info!(
+                "Restoring extended attributes of {} files from /init",
+                xattrs.len()
+            );
+            restored_xattrs = xattrs.len();
+            let wrapped_init = unused_path(&archive, WRAPPED_INIT);
+            archive.add_file(&wrapped_init, init, EntryAttributes::new(0o755));
+            archive.add_file(
+                "init",
+                restore_init(&xattrs, &wrapped_init),
+                EntryAttributes::new(0o755),
+            );
+        } else {
+            // A shell script as /init would leave such images (distroless, static
+            // binaries) unbootable
+            warn!(
+                "The image has no /bin/sh or setfattr, extended attributes of {} files are not restored",
+                xattrs.len()
+            );
+            dropped_xattrs = xattrs
+                .iter()
+                .map(|(path, xattrs)| {
+                    let names = xattrs.iter().map(|xattr| xattr.name.clone()).collect();
+                    (Path::new("/").join(path), names)
+                })
+                .collect();
+            archive.add_file("init", init, EntryAttributes::new(0o755));
         }
+        archive.set_owners(self.owners);
 
-        let mut perms = fs::metadata(&init_dest)?.permissions();
-        perms.set_mode(0o755);
-        fs::set_permissions(&init_dest, perms)?;
-
-        info!("Creating CPIO archive from {:?}", rootfs_path);
-
-        let archive = CpioArchive::from_directory(&rootfs_path)?;
+        let source_date_epoch = if self.reproducible {
+            let mut epoch = self.source_date_epoch;
+            if epoch.is_none() {
+                epoch = source_date_epoch_from_env()?;
+            }
+            if let (None, Some(builder)) = (epoch, &rootfs_builder) {
+                epoch = builder.created().await?;
+            }
+            let epoch = epoch.unwrap_or(0);
+            info!("Reproducible build, clamping timestamps to {}", epoch);
+            archive.set_reproducible(epoch.min(u32::MAX as u64) as u32);
+            Some(epoch)
+        } else {
+            None
+        };
 
         let mut cpio_data = Vec::new();
         archive.write_to(&mut cpio_data)?;
@@ -221,10 +420,89 @@ exec /bin/sh
             compression: self.compression,
             injected_files: self.inject_files.len(),
             has_custom_init: self.init_script.is_some(),
+            restored_xattrs,
+            dropped_xattrs,
+            source_date_epoch,
+            rejected_entries: rootfs_builder
+                .map(|builder| builder.rejected_entries().to_vec())
+                .unwrap_or_default(),
         })
     }
 }
 
+// `build` runs on spawned tasks (see the TUI), so its future must stay `Send`
+const _: fn(InitramfsBuilder) = |builder| {
+    fn assert_send<T: Send>(_: T) {}
+    assert_send(builder.build("initramfs.cpio.gz"));
+};
+
+/// `$SOURCE_DATE_EPOCH`, see <https://reproducible-builds.org/specs/source-date-epoch/>
+fn source_date_epoch_from_env() -> anyhow::Result<Option<u64>> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(value) if !value.is_empty() => value
+            .trim()
+            .parse()
+            .map(Some)
+            .with_context(|| format!("Invalid SOURCE_DATE_EPOCH {:?}", value)),
+        _ => Ok(None),
+    }
+}
+
+/// `path` relative to the root of the archive, as the image metadata records it
+fn archive_relative(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
+
+/// `preferred`, or the first of `preferred.1`, `preferred.2`... free in `archive`
+fn unused_path(archive: &CpioArchive, preferred: &str) -> String {
+    let mut path = preferred.to_string();
+    let mut suffix = 0;
+    while archive.contains(&path) {
+        suffix += 1;
+        path = format!("{}.{}", preferred, suffix);
+    }
+    path
+}
+
+/// Directories `command -v` searches when the kernel starts /init without `$PATH`
+const INIT_PATH: [&str; 4] = ["usr/sbin", "usr/bin", "sbin", "bin"];
+
+/// Whether the generated /init restoring extended attributes can run in `archive`
+fn can_restore_xattrs(archive: &CpioArchive) -> bool {
+    archive.resolve("bin/sh").is_some()
+        && INIT_PATH
+            .iter()
+            .any(|dir| archive.resolve(format!("{}/setfattr", dir)).is_some())
+}
+
+/// Device nodes added when the rootfs lacks them, needed before devtmpfs is mounted
+const EARLY_DEVICES: [(&str, SpecialFile, u32); 2] = [
+    (
+        "dev/console",
+        SpecialFile::CharDevice { major: 5, minor: 1 },
+        0o600,
+    ),
+    (
+        "dev/null",
+        SpecialFile::CharDevice { major: 1, minor: 3 },
+        0o666,
+    ),
+];
+
+const DEFAULT_INIT: &str = r#"#!/bin/sh
+mount -t proc proc /proc 2>/dev/null
+mount -t sysfs sysfs /sys 2>/dev/null
+mount -t devtmpfs devtmpfs /dev 2>/dev/null
+
+for cmd in /docker-entrypoint.sh /entrypoint.sh /usr/bin/entrypoint.sh; do
+    [ -x "$cmd" ] && exec "$cmd"
+done
+
+exec /bin/sh
+"#;
+
 impl Default for InitramfsBuilder {
     fn default() -> Self {
         Self::new()
@@ -239,4 +517,13 @@ pub struct BuildResult {
     pub compression: Compression,
     pub injected_files: usize,
     pub has_custom_init: bool,
+    /// Files whose extended attributes `/init` restores before running the real init
+    pub restored_xattrs: usize,
+    /// Files whose extended attributes are lost, with the attribute names, because
+    /// the image has no `/bin/sh` or `setfattr` to restore them at boot
+    pub dropped_xattrs: Vec<(PathBuf, Vec<String>)>,
+    /// Timestamp the entries were clamped to, for reproducible builds
+    pub source_date_epoch: Option<u64>,
+    /// Image entries left out because they tried to escape the rootfs
+    pub rejected_entries: Vec<RejectedEntry>,
 }
@@ -1,9 +1,13 @@
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use indicatif::{ProgressBar, ProgressStyle};
-use initramfs_builder::{Compression, InitramfsBuilder, RegistryAuth, RegistryClient};
+use initramfs_builder::{
+    BlobCache, Compression, InitramfsBuilder, PullOptions, RegistryAuth, RegistryClient,
+    RegistryConfig, RetryPolicy,
+};
 use std::io::{self, BufRead};
 use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
 use tracing_subscriber::EnvFilter;
 
 #[derive(Parser)]
@@ -20,12 +24,19 @@ struct Cli {
 
 mod tui;
 
+// Parsed once at startup, boxing the `Build` arguments would buy nothing
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
     /// Build an initramfs from a Docker/OCI image
     Build {
         /// Image reference (e.g., python:3.11-alpine)
-        image: String,
+        #[arg(required_unless_present = "rootfs")]
+        image: Option<String>,
+
+        /// Build from a prepared root filesystem directory instead of an image
+        #[arg(long, value_name = "DIR", conflicts_with_all = ["image", "exclude"])]
+        rootfs: Option<PathBuf>,
 
         /// Output file path
         #[arg(short, long, default_value = "initramfs.cpio.gz")]
@@ -47,6 +58,19 @@ enum Commands {
         #[arg(long, value_name = "PATH")]
         init: Option<PathBuf>,
 
+        /// Make every file in the archive owned by OWNER (root or UID:GID)
+        #[arg(long, value_name = "OWNER", value_parser = parse_owner)]
+        squash_owner: Option<(u32, u32)>,
+
+        /// Owner of a path and everything below it in the archive (format: /path=UID:GID, can be repeated)
+        #[arg(long, value_name = "PATH=OWNER", value_parser = parse_path_owner)]
+        owner: Vec<(PathBuf, (u32, u32))>,
+
+        /// Byte-for-byte deterministic output: sorted entries, timestamps clamped to
+        /// $SOURCE_DATE_EPOCH or the image creation date
+        #[arg(long)]
+        reproducible: bool,
+
         /// Target platform OS
         #[arg(long, default_value = "linux")]
         platform_os: String,
@@ -55,6 +79,22 @@ enum Commands {
         #[arg(long, default_value = "amd64")]
         platform_arch: String,
 
+        /// Number of layers downloaded in parallel
+        #[arg(long, default_value = "3", value_name = "N")]
+        max_concurrent_downloads: usize,
+
+        /// Directory of the layer cache (default: ~/.cache/initramfs-builder)
+        #[arg(long, value_name = "DIR")]
+        cache_dir: Option<PathBuf>,
+
+        /// Always download layers, without reading or filling the cache
+        #[arg(long, conflicts_with = "cache_dir")]
+        no_cache: bool,
+
+        /// Hash cached layers again before using them
+        #[arg(long, conflicts_with = "no_cache")]
+        verify_cache: bool,
+
         /// Registry username
         #[arg(long)]
         username: Option<String>,
@@ -62,6 +102,13 @@ enum Commands {
         /// Read password from stdin
         #[arg(long)]
         password_stdin: bool,
+
+        /// Read a registry bearer token from stdin
+        #[arg(long, conflicts_with_all = ["username", "password_stdin"])]
+        registry_token_stdin: bool,
+
+        #[command(flatten)]
+        registry: RegistryArgs,
     },
 
     /// Inspect an image (show manifest info)
@@ -76,6 +123,9 @@ enum Commands {
         /// Target platform architecture
         #[arg(long, default_value = "amd64")]
         platform_arch: String,
+
+        #[command(flatten)]
+        registry: RegistryArgs,
     },
 
     /// List layers of an image
@@ -90,12 +140,93 @@ enum Commands {
         /// Target platform architecture
         #[arg(long, default_value = "amd64")]
         platform_arch: String,
+
+        #[command(flatten)]
+        registry: RegistryArgs,
+    },
+
+    /// Manage the local layer cache
+    Cache {
+        /// Directory of the layer cache (default: ~/.cache/initramfs-builder)
+        #[arg(long, global = true, value_name = "DIR")]
+        cache_dir: Option<PathBuf>,
+
+        #[command(subcommand)]
+        command: CacheCommand,
     },
 
     /// Interactive mode (TUI)
     Interactive,
 }
 
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// List cached blobs
+    Ls,
+
+    /// Remove blobs not used recently
+    Prune {
+        /// Minimum age of removed blobs (e.g. 30d, 12h, 90m, 3600)
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+        older_than: Duration,
+    },
+
+    /// Show the disk space used by the cache
+    Du,
+}
+
+#[derive(Args)]
+struct RegistryArgs {
+    /// Registry to reach over plain HTTP (host:port, can be repeated)
+    #[arg(long, value_name = "HOST:PORT")]
+    insecure_registry: Vec<String>,
+
+    /// Extra CA certificate (PEM) to trust for registry TLS (can be repeated)
+    #[arg(long, value_name = "PATH")]
+    registry_ca_cert: Vec<PathBuf>,
+
+    /// Registry mirror, tried before the upstream (format: registry=mirror, can be repeated)
+    #[arg(long, value_name = "REGISTRY=MIRROR")]
+    registry_mirror: Vec<String>,
+
+    /// Retries of registry requests after transient failures (5xx, 429, dropped connections)
+    #[arg(long, default_value = "4", value_name = "N")]
+    max_retries: u32,
+}
+
+impl RegistryArgs {
+    fn config(&self) -> RegistryConfig {
+        RegistryConfig {
+            insecure_registries: self.insecure_registry.clone(),
+            ca_certificates: self.registry_ca_cert.clone(),
+        }
+    }
+
+    fn mirrors(&self) -> Result<Vec<(String, String)>> {
+        self.registry_mirror
+            .iter()
+            .map(|s| parse_mirror(s))
+            .collect()
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::default().max_retries(self.max_retries)
+    }
+
+    fn pull_options(&self, platform_os: String, platform_arch: String) -> Result<PullOptions> {
+        let mut options = PullOptions {
+            platform_os,
+            platform_arch,
+            retry: self.retry_policy(),
+            ..Default::default()
+        };
+        for (registry, mirror) in self.mirrors()? {
+            options = options.mirror(&registry, &mirror);
+        }
+        Ok(options)
+    }
+}
+
 fn setup_logging(verbose: bool) {
     let filter = if verbose {
         EnvFilter::new("debug")
@@ -109,7 +240,7 @@ fn setup_logging(verbose: bool) {
         .init();
 }
 
-fn read_password_stdin() -> Result<String> {
+fn read_secret_stdin() -> Result<String> {
     let stdin = io::stdin();
     let mut line = String::new();
     stdin.lock().read_line(&mut line)?;
@@ -132,6 +263,87 @@ fn format_size(bytes: u64) -> String {
     }
 }
 
+/// Parse mirror argument in format "registry=mirror"
+fn parse_mirror(s: &str) -> Result<(String, String)> {
+    match s.split_once('=') {
+        Some((registry, mirror)) if !registry.is_empty() && !mirror.is_empty() => {
+            Ok((registry.to_string(), mirror.to_string()))
+        }
+        _ => anyhow::bail!(
+            "Invalid mirror format '{}'. Expected format: docker.io=mirror.example.com",
+            s
+        ),
+    }
+}
+
+/// Parse a duration such as "30d", "12h", "90m", "45s" or plain seconds
+fn parse_duration(s: &str) -> Result<Duration> {
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => s.split_at(idx),
+        None => (s, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => anyhow::bail!(
+            "Invalid duration '{}'. Expected a number with s, m, h or d",
+            s
+        ),
+    };
+    let value: u64 = value
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid duration '{}'", s))?;
+    let seconds = value
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow::anyhow!("Duration '{}' is too long", s))?;
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Parse an owner: "root" or "UID:GID"
+fn parse_owner(s: &str) -> Result<(u32, u32)> {
+    if s == "root" {
+        return Ok((0, 0));
+    }
+    s.split_once(':')
+        .and_then(|(uid, gid)| Some((uid.parse().ok()?, gid.parse().ok()?)))
+        .ok_or_else(|| anyhow::anyhow!("Invalid owner '{}'. Expected root or UID:GID", s))
+}
+
+/// Parse a path owner argument in format "/path=UID:GID"
+fn parse_path_owner(s: &str) -> Result<(PathBuf, (u32, u32))> {
+    match s.rsplit_once('=') {
+        Some((path, owner)) if !path.is_empty() => Ok((PathBuf::from(path), parse_owner(owner)?)),
+        _ => anyhow::bail!(
+            "Invalid owner format '{}'. Expected format: /var/lib/agent=1000:1000",
+            s
+        ),
+    }
+}
+
+/// Cache at `dir`, or in the default location
+fn open_cache(dir: Option<PathBuf>) -> Result<BlobCache> {
+    match dir {
+        Some(dir) => Ok(BlobCache::new(dir)),
+        None => BlobCache::open_default(),
+    }
+}
+
+/// Human readable time since `time`
+fn format_age(time: SystemTime) -> String {
+    let secs = SystemTime::now()
+        .duration_since(time)
+        .unwrap_or_default()
+        .as_secs();
+    match secs {
+        s if s >= 24 * 60 * 60 => format!("{}d ago", s / (24 * 60 * 60)),
+        s if s >= 60 * 60 => format!("{}h ago", s / (60 * 60)),
+        s if s >= 60 => format!("{}m ago", s / 60),
+        s => format!("{}s ago", s),
+    }
+}
+
 /// Parse inject argument in format "src:dest"
 fn parse_inject(s: &str) -> Result<(PathBuf, PathBuf)> {
     let parts: Vec<&str> = s.splitn(2, ':').collect();
@@ -151,15 +363,25 @@ async fn main() -> Result<()> {
     match cli.command {
         Commands::Build {
             image,
+            rootfs,
             output,
             compression,
             exclude,
             inject,
             init,
+            squash_owner,
+            owner,
+            reproducible,
             platform_os,
             platform_arch,
+            max_concurrent_downloads,
+            cache_dir,
+            no_cache,
+            verify_cache,
             username,
             password_stdin,
+            registry_token_stdin,
+            registry,
         } => {
             setup_logging(cli.verbose);
             let compression: Compression = compression
@@ -167,8 +389,11 @@ async fn main() -> Result<()> {
                 .map_err(|e: String| anyhow::anyhow!(e))?;
 
             let auth = match (username, password_stdin) {
+                _ if registry_token_stdin => RegistryAuth::Bearer {
+                    token: read_secret_stdin()?,
+                },
                 (Some(user), true) => {
-                    let password = read_password_stdin()?;
+                    let password = read_secret_stdin()?;
                     RegistryAuth::Basic {
                         username: user,
                         password,
@@ -190,15 +415,27 @@ async fn main() -> Result<()> {
                     .template("{spinner:.green} {msg}")
                     .unwrap(),
             );
-            pb.set_message(format!("Building initramfs from {}...", image));
             pb.enable_steady_tick(std::time::Duration::from_millis(100));
 
             let exclude_refs: Vec<&str> = exclude.iter().map(|s| s.as_str()).collect();
 
-            let mut builder = InitramfsBuilder::new()
-                .image(&image)
+            let builder = match (&rootfs, &image) {
+                (Some(dir), _) => {
+                    pb.set_message(format!("Building initramfs from {}...", dir.display()));
+                    InitramfsBuilder::from_directory(dir)
+                }
+                (None, Some(image)) => {
+                    pb.set_message(format!("Building initramfs from {}...", image));
+                    InitramfsBuilder::new().image(image)
+                }
+                (None, None) => anyhow::bail!("Either an image or --rootfs is required"),
+            };
+
+            let mut builder = builder
                 .compression(compression)
                 .platform(&platform_os, &platform_arch)
+                .max_concurrent_downloads(max_concurrent_downloads)
+                .retry_policy(registry.retry_policy())
                 .auth(auth);
 
             for pattern in &exclude_refs {
@@ -214,6 +451,35 @@ async fn main() -> Result<()> {
                 builder = builder.init_script(init_path);
             }
 
+            if let Some((uid, gid)) = squash_owner {
+                builder = builder.owner_override(uid, gid);
+            }
+            for (path, (uid, gid)) in owner {
+                builder = builder.path_owner(path, uid, gid);
+            }
+            if reproducible {
+                builder = builder.reproducible();
+            }
+
+            for (upstream, mirror) in registry.mirrors()? {
+                builder = builder.registry_mirror(&upstream, &mirror);
+            }
+
+            let registry = registry.config();
+            for host in &registry.insecure_registries {
+                builder = builder.insecure_registry(host);
+            }
+            for cert in registry.ca_certificates {
+                builder = builder.ca_certificate(cert);
+            }
+
+            if !no_cache {
+                match open_cache(cache_dir) {
+                    Ok(cache) => builder = builder.blob_cache(cache.verify_hits(verify_cache)),
+                    Err(e) => eprintln!("Warning: layer cache disabled: {:#}", e),
+                }
+            }
+
             let result = builder.build(&output).await?;
 
             pb.finish_and_clear();
@@ -233,20 +499,45 @@ async fn main() -> Result<()> {
             if result.has_custom_init {
                 println!("  Custom init: yes");
             }
+            if let Some(epoch) = result.source_date_epoch {
+                println!("  Reproducible: timestamps clamped to {}", epoch);
+            }
+            if result.restored_xattrs > 0 {
+                println!(
+                    "  Extended attributes: {} files (restored by /init)",
+                    result.restored_xattrs
+                );
+            }
+            if !result.dropped_xattrs.is_empty() {
+                println!(
+                    "  Extended attributes: {} files NOT restored (no /bin/sh or setfattr in the image)",
+                    result.dropped_xattrs.len()
+                );
+                for (path, names) in &result.dropped_xattrs {
+                    println!("    {}: {}", path.display(), names.join(", "));
+                }
+            }
+            if !result.rejected_entries.is_empty() {
+                println!(
+                    "  Rejected entries: {} (tried to escape the rootfs)",
+                    result.rejected_entries.len()
+                );
+                for entry in &result.rejected_entries {
+                    println!("    {}: {}", entry.path.display(), entry.reason);
+                }
+            }
         }
 
         Commands::Inspect {
             image,
             platform_os,
             platform_arch,
+            registry,
         } => {
             setup_logging(cli.verbose);
-            let client = RegistryClient::new(RegistryAuth::Anonymous);
+            let client = RegistryClient::with_config(RegistryAuth::Anonymous, registry.config())?;
             let reference = RegistryClient::parse_reference(&image)?;
-            let options = initramfs_builder::PullOptions {
-                platform_os,
-                platform_arch,
-            };
+            let options = registry.pull_options(platform_os, platform_arch)?;
 
             let manifest = client.fetch_manifest(&reference, &options).await?;
 
@@ -260,14 +551,12 @@ async fn main() -> Result<()> {
             image,
             platform_os,
             platform_arch,
+            registry,
         } => {
             setup_logging(cli.verbose);
-            let client = RegistryClient::new(RegistryAuth::Anonymous);
+            let client = RegistryClient::with_config(RegistryAuth::Anonymous, registry.config())?;
             let reference = RegistryClient::parse_reference(&image)?;
-            let options = initramfs_builder::PullOptions {
-                platform_os,
-                platform_arch,
-            };
+            let options = registry.pull_options(platform_os, platform_arch)?;
 
             let manifest = client.fetch_manifest(&reference, &options).await?;
 
@@ -285,6 +574,43 @@ async fn main() -> Result<()> {
             println!("{}", format_size(manifest.total_size));
         }
 
+        Commands::Cache { cache_dir, command } => {
+            setup_logging(cli.verbose);
+            let cache = open_cache(cache_dir)?;
+
+            match command {
+                CacheCommand::Ls => {
+                    let entries = cache.entries()?;
+                    for entry in &entries {
+                        println!(
+                            "{}  {:>12}  {}",
+                            entry.digest,
+                            format_size(entry.size),
+                            format_age(entry.modified)
+                        );
+                    }
+                    println!();
+                    println!("{} blobs in {}", entries.len(), cache.root().display());
+                }
+                CacheCommand::Prune { older_than } => {
+                    let removed = cache.prune(older_than)?;
+                    let freed: u64 = removed.iter().map(|e| e.size).sum();
+                    println!(
+                        "Removed {} blobs, freed {}",
+                        removed.len(),
+                        format_size(freed)
+                    );
+                }
+                CacheCommand::Du => {
+                    println!(
+                        "{}\t{}",
+                        format_size(cache.disk_usage()?),
+                        cache.root().display()
+                    );
+                }
+            }
+        }
+
         Commands::Interactive => {
             tui::run().await?;
         }
@@ -0,0 +1,395 @@
+use anyhow::{Context, Result};
+use std::ffi::OsString;
+use std::fs;
+use std::fs::TryLockError;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tempfile::NamedTempFile;
+use tracing::{debug, warn};
+
+use super::digest::DigestVerifier;
+
+/// Content-addressed store of downloaded blobs (`<root>/blobs/<algorithm>/<hex>`)
+#[derive(Debug, Clone)]
+pub struct BlobCache {
+    root: PathBuf,
+    verify_hits: bool,
+}
+
+/// A blob stored in the cache
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    pub digest: String,
+    pub size: u64,
+    /// Last time the blob was downloaded or used
+    pub modified: SystemTime,
+    pub path: PathBuf,
+}
+
+impl BlobCache {
+    /// Use `root` as the cache directory (created on first write)
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            verify_hits: false,
+        }
+    }
+
+    /// Hash cached blobs again on every hit, to catch corruption on disk
+    ///
+    /// Blobs are verified when they are downloaded, so this is off by default.
+    pub fn verify_hits(mut self, verify: bool) -> Self {
+        self.verify_hits = verify;
+        self
+    }
+
+    /// `$XDG_CACHE_HOME/initramfs-builder`, or `~/.cache/initramfs-builder`
+    pub fn default_dir() -> Option<PathBuf> {
+        cache_root(std::env::var_os("XDG_CACHE_HOME"), std::env::var_os("HOME"))
+    }
+
+    /// Cache in [`Self::default_dir`]
+    pub fn open_default() -> Result<Self> {
+        Self::default_dir()
+            .map(Self::new)
+            .context("Cannot locate the cache directory, set HOME or XDG_CACHE_HOME")
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Path where the blob `digest` is (or would be) stored
+    pub fn blob_path(&self, digest: &str) -> Result<PathBuf> {
+        let (algorithm, hex) = digest
+            .split_once(':')
+            .with_context(|| format!("Invalid digest {}", digest))?;
+        if algorithm.is_empty()
+            || hex.is_empty()
+            || !algorithm.chars().all(|c| c.is_ascii_alphanumeric())
+            || !hex.chars().all(|c| c.is_ascii_hexdigit())
+        {
+            anyhow::bail!("Invalid digest {}", digest);
+        }
+        Ok(self.root.join("blobs").join(algorithm).join(hex))
+    }
+
+    /// Path of a cached blob of the expected size, if present
+    ///
+    /// With [`Self::verify_hits`], the blob is hashed again and a corrupted or
+    /// tampered one is evicted so it gets downloaded again. A hit refreshes the
+    /// blob's modification time so `prune` keeps it.
+    pub fn get(&self, digest: &str, size: u64) -> Option<PathBuf> {
+        let path = self.blob_path(digest).ok()?;
+        let metadata = fs::metadata(&path).ok()?;
+        if metadata.len() != size {
+            warn!("Ignoring cached {} with unexpected size", digest);
+            return None;
+        }
+        if self.verify_hits {
+            if let Err(e) = verify_file(&path, digest, size) {
+                warn!("Evicting cached {}: {:#}", digest, e);
+                if let Err(e) = fs::remove_file(&path) {
+                    debug!("Failed to remove {:?}: {}", path, e);
+                }
+                return None;
+            }
+        }
+
+        if let Err(e) = fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|f| f.set_modified(SystemTime::now()))
+        {
+            debug!("Failed to touch cached {}: {}", digest, e);
+        }
+
+        debug!("Cache hit for {}", digest);
+        Some(path)
+    }
+
+    /// Temporary file to download a blob into before [`Self::commit`]
+    pub fn temp_file(&self) -> Result<NamedTempFile> {
+        let dir = self.root.join("tmp");
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {:?}", dir))?;
+        NamedTempFile::new_in(&dir).with_context(|| format!("Failed to create file in {:?}", dir))
+    }
+
+    /// Move a download into the cache, returning its final path
+    ///
+    /// The caller has checked the file against `digest`.
+    pub fn commit(&self, file: NamedTempFile, digest: &str) -> Result<PathBuf> {
+        let path = self.blob_path(digest)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        file.persist(&path)
+            .with_context(|| format!("Failed to store {} in the cache", digest))?;
+        Ok(path)
+    }
+
+    /// Where an in-progress download of `digest` is kept, so it can be resumed later
+    pub fn partial_path(&self, digest: &str) -> Result<PathBuf> {
+        // Validates the digest before it becomes part of a file name
+        self.blob_path(digest)?;
+        let dir = self.root.join("tmp");
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {:?}", dir))?;
+        Ok(dir.join(format!("{}.partial", digest.replace(':', "-"))))
+    }
+
+    /// Open the partial download of `digest` with an exclusive lock on it
+    ///
+    /// Blocks while another task or build holds the lock, which is released
+    /// when the returned file is dropped.
+    pub fn lock_partial(&self, digest: &str) -> Result<fs::File> {
+        let path = self.partial_path(digest)?;
+        let file = fs::File::options()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Failed to open {:?}", path))?;
+        file.lock()
+            .with_context(|| format!("Failed to lock {:?}", path))?;
+        Ok(file)
+    }
+
+    /// Move a completed partial download into the cache
+    ///
+    /// The caller has checked the file against `digest`.
+    pub fn commit_partial(&self, partial: &Path, digest: &str) -> Result<PathBuf> {
+        let path = self.blob_path(digest)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(partial, &path)
+            .with_context(|| format!("Failed to store {} in the cache", digest))?;
+        Ok(path)
+    }
+
+    /// Store an in-memory blob
+    pub fn insert(&self, digest: &str, data: &[u8]) -> Result<PathBuf> {
+        let mut file = self.temp_file()?;
+        std::io::Write::write_all(&mut file, data)?;
+        self.commit(file, digest)
+    }
+
+    /// Every cached blob, sorted by digest
+    pub fn entries(&self) -> Result<Vec<CacheEntry>> {
+        let blobs = self.root.join("blobs");
+        let mut entries = Vec::new();
+        if !blobs.exists() {
+            return Ok(entries);
+        }
+
+        for algorithm in fs::read_dir(&blobs)? {
+            let algorithm = algorithm?;
+            if !algorithm.file_type()?.is_dir() {
+                continue;
+            }
+            for blob in fs::read_dir(algorithm.path())? {
+                let blob = blob?;
+                let metadata = blob.metadata()?;
+                if !metadata.is_file() {
+                    continue;
+                }
+                entries.push(CacheEntry {
+                    digest: format!(
+                        "{}:{}",
+                        algorithm.file_name().to_string_lossy(),
+                        blob.file_name().to_string_lossy()
+                    ),
+                    size: metadata.len(),
+                    modified: metadata.modified()?,
+                    path: blob.path(),
+                });
+            }
+        }
+
+        entries.sort_by(|a, b| a.digest.cmp(&b.digest));
+        Ok(entries)
+    }
+
+    /// Total size of the cached blobs in bytes
+    pub fn disk_usage(&self) -> Result<u64> {
+        Ok(self.entries()?.iter().map(|e| e.size).sum())
+    }
+
+    /// Remove blobs not used for `age`, returning the removed entries
+    pub fn prune(&self, age: Duration) -> Result<Vec<CacheEntry>> {
+        let cutoff = SystemTime::now()
+            .checked_sub(age)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+
+        let mut removed = Vec::new();
+        for entry in self.entries()? {
+            if entry.modified <= cutoff {
+                fs::remove_file(&entry.path)
+                    .with_context(|| format!("Failed to remove {:?}", entry.path))?;
+                removed.push(entry);
+            }
+        }
+
+        // Leftovers of interrupted downloads, unless a build is resuming them
+        let tmp = self.root.join("tmp");
+        if tmp.exists() {
+            for file in fs::read_dir(&tmp)? {
+                let file = file?;
+                if file.metadata()?.modified()? > cutoff {
+                    continue;
+                }
+                let path = file.path();
+                let Ok(handle) = fs::File::open(&path) else {
+                    continue;
+                };
+                match handle.try_lock() {
+                    Ok(()) => {
+                        if let Err(e) = fs::remove_file(&path) {
+                            debug!("Failed to remove {:?}: {}", path, e);
+                        }
+                    }
+                    Err(TryLockError::WouldBlock) => {
+                        debug!("Keeping {:?}, a download holds it", path);
+                    }
+                    Err(TryLockError::Error(e)) => {
+                        debug!("Failed to lock {:?}: {}", path, e);
+                    }
+                }
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+/// Check the file at `path` against `digest` and `size`
+fn verify_file(path: &Path, digest: &str, size: u64) -> Result<()> {
+    let mut verifier = DigestVerifier::new(digest, Some(size))?;
+    let mut file = fs::File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        verifier.update(&buf[..read]);
+    }
+    Ok(verifier.finish()?)
+}
+
+/// Cache directory given `$XDG_CACHE_HOME` and `$HOME`
+fn cache_root(xdg_cache_home: Option<OsString>, home: Option<OsString>) -> Option<PathBuf> {
+    let base = match xdg_cache_home {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(home?).join(".cache"),
+    };
+    Some(base.join("initramfs-builder"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const DIGEST: &str = "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn test_cache_root() {
+        assert_eq!(
+            cache_root(Some("/xdg".into()), Some("/home/me".into())),
+            Some(PathBuf::from("/xdg/initramfs-builder"))
+        );
+        assert_eq!(
+            cache_root(None, Some("/home/me".into())),
+            Some(PathBuf::from("/home/me/.cache/initramfs-builder"))
+        );
+        assert_eq!(cache_root(None, None), None);
+    }
+
+    #[test]
+    fn test_insert_and_get() {
+        let dir = TempDir::new().unwrap();
+        let cache = BlobCache::new(dir.path());
+
+        assert!(cache.get(DIGEST, 5).is_none());
+        let path = cache.insert(DIGEST, b"hello").unwrap();
+        assert_eq!(path, dir.path().join("blobs/sha256").join(&DIGEST[7..]));
+        assert_eq!(cache.get(DIGEST, 5), Some(path));
+        assert!(cache.get(DIGEST, 6).is_none());
+
+        assert!(cache.blob_path("sha256:../../etc/passwd").is_err());
+    }
+
+    #[test]
+    fn test_corrupted_blob_is_evicted() {
+        let dir = TempDir::new().unwrap();
+        let cache = BlobCache::new(dir.path());
+
+        // Same size as the expected blob, different content
+        let path = cache.insert(DIGEST, b"jello").unwrap();
+        assert_eq!(cache.get(DIGEST, 5), Some(path.clone()));
+
+        let cache = cache.verify_hits(true);
+        assert!(cache.get(DIGEST, 5).is_none());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_commit_partial() {
+        let dir = TempDir::new().unwrap();
+        let cache = BlobCache::new(dir.path());
+
+        let partial = cache.partial_path(DIGEST).unwrap();
+        fs::write(&partial, b"hello").unwrap();
+        let path = cache.commit_partial(&partial, DIGEST).unwrap();
+
+        assert!(!partial.exists());
+        assert_eq!(cache.get(DIGEST, 5), Some(path));
+        assert!(cache.partial_path("sha256:../x").is_err());
+    }
+
+    #[test]
+    fn test_lock_partial() {
+        let dir = TempDir::new().unwrap();
+        let cache = BlobCache::new(dir.path());
+
+        let lock = cache.lock_partial(DIGEST).unwrap();
+        let other = fs::File::open(cache.partial_path(DIGEST).unwrap()).unwrap();
+        assert!(other.try_lock().is_err());
+
+        drop(lock);
+        assert!(other.try_lock().is_ok());
+    }
+
+    #[test]
+    fn test_entries_and_prune() {
+        let dir = TempDir::new().unwrap();
+        let cache = BlobCache::new(dir.path());
+        cache.insert(DIGEST, b"hello").unwrap();
+
+        let entries = cache.entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].digest, DIGEST);
+        assert_eq!(cache.disk_usage().unwrap(), 5);
+
+        assert!(cache.prune(Duration::from_secs(3600)).unwrap().is_empty());
+        assert_eq!(cache.prune(Duration::ZERO).unwrap().len(), 1);
+        assert_eq!(cache.disk_usage().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_prune_keeps_locked_partials() {
+        let dir = TempDir::new().unwrap();
+        let cache = BlobCache::new(dir.path());
+        let partial = cache.partial_path(DIGEST).unwrap();
+
+        let lock = cache.lock_partial(DIGEST).unwrap();
+        cache.prune(Duration::ZERO).unwrap();
+        assert!(partial.exists());
+
+        drop(lock);
+        cache.prune(Duration::ZERO).unwrap();
+        assert!(!partial.exists());
+    }
+}
@@ -1,13 +1,40 @@
 use anyhow::{Context, Result};
-use oci_distribution::{
-    client::{Client, ClientConfig, ClientProtocol},
-    manifest::OciDescriptor,
+use futures::stream::{self, StreamExt, TryStreamExt};
+use oci_client::{
+    client::{Certificate, CertificateEncoding, Client, ClientConfig, ClientProtocol},
+    manifest::{
+        ImageIndexEntry, OciImageManifest, OciManifest, IMAGE_MANIFEST_LIST_MEDIA_TYPE,
+        IMAGE_MANIFEST_MEDIA_TYPE, OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE,
+    },
     secrets::RegistryAuth as OciRegistryAuth,
-    Reference,
+    Reference, RegistryOperation,
 };
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-use tracing::{debug, info};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, info, warn};
+
+use super::cache::BlobCache;
+use super::credentials::DockerConfig;
+use super::digest::{verify_blob, VerifyingWriter};
+use super::retry::{self, RangeMismatch, RetryPolicy, StatusError};
+use super::token::exchange_identity_token;
+
+const CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+
+const MANIFEST_MEDIA_TYPES: &[&str] = &[
+    OCI_IMAGE_MEDIA_TYPE,
+    OCI_IMAGE_INDEX_MEDIA_TYPE,
+    IMAGE_MANIFEST_MEDIA_TYPE,
+    IMAGE_MANIFEST_LIST_MEDIA_TYPE,
+];
+
+/// Same default as the Docker daemon
+const DEFAULT_CONCURRENT_DOWNLOADS: usize = 3;
 
 /// Authentication credentials for a registry
 #[derive(Debug, Clone, Default)]
@@ -18,17 +45,14 @@ pub enum RegistryAuth {
         username: String,
         password: String,
     },
-}
-
-impl From<RegistryAuth> for OciRegistryAuth {
-    fn from(auth: RegistryAuth) -> Self {
-        match auth {
-            RegistryAuth::Anonymous => OciRegistryAuth::Anonymous,
-            RegistryAuth::Basic { username, password } => {
-                OciRegistryAuth::Basic(username, password)
-            }
-        }
-    }
+    /// Static bearer token sent as-is (e.g. a registry access token)
+    Bearer {
+        token: String,
+    },
+    /// Identity (refresh) token, exchanged for an access token before pulling
+    IdentityToken {
+        token: String,
+    },
 }
 
 /// Options for pulling an image
@@ -36,6 +60,13 @@ impl From<RegistryAuth> for OciRegistryAuth {
 pub struct PullOptions {
     pub platform_os: String,
     pub platform_arch: String,
+    /// Mirror hosts per upstream registry (e.g. `docker.io` -> `["mirror.gcr.io"]`),
+    /// tried in order before falling back to the upstream registry
+    pub mirrors: HashMap<String, Vec<String>>,
+    /// Number of layers downloaded at the same time (layers are still applied in order)
+    pub max_concurrent_downloads: usize,
+    /// Retries of manifest and blob requests after transient failures
+    pub retry: RetryPolicy,
 }
 
 impl Default for PullOptions {
@@ -43,10 +74,66 @@ impl Default for PullOptions {
         Self {
             platform_os: "linux".to_string(),
             platform_arch: "amd64".to_string(),
+            mirrors: HashMap::new(),
+            max_concurrent_downloads: DEFAULT_CONCURRENT_DOWNLOADS,
+            retry: RetryPolicy::default(),
         }
     }
 }
 
+impl PullOptions {
+    /// Add a mirror for an upstream registry
+    pub fn mirror(mut self, registry: &str, mirror: &str) -> Self {
+        self.mirrors
+            .entry(registry.to_string())
+            .or_default()
+            .push(mirror.to_string());
+        self
+    }
+
+    /// Download up to `count` layers at the same time
+    pub fn concurrent_downloads(mut self, count: usize) -> Self {
+        self.max_concurrent_downloads = count.max(1);
+        self
+    }
+
+    /// Retry transient registry failures according to `policy`
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// References to try for `reference`: its mirrors in order, then the upstream
+    fn candidates(&self, reference: &Reference) -> Vec<Reference> {
+        let mut candidates: Vec<Reference> = self
+            .mirrors
+            .get(reference.registry())
+            .into_iter()
+            .flatten()
+            .filter_map(|mirror| {
+                let mut image = format!("{}/{}", mirror, reference.repository());
+                if let Some(tag) = reference.tag() {
+                    image.push(':');
+                    image.push_str(tag);
+                }
+                if let Some(digest) = reference.digest() {
+                    image.push('@');
+                    image.push_str(digest);
+                }
+                match image.parse() {
+                    Ok(mirrored) => Some(mirrored),
+                    Err(e) => {
+                        warn!("Ignoring invalid mirror {}: {}", mirror, e);
+                        None
+                    }
+                }
+            })
+            .collect();
+        candidates.push(reference.clone());
+        candidates
+    }
+}
+
 /// Describes a layer in an OCI image
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LayerDescriptor {
@@ -55,39 +142,310 @@ pub struct LayerDescriptor {
     pub media_type: String,
 }
 
-impl LayerDescriptor {
-    fn to_oci_descriptor(&self) -> OciDescriptor {
-        OciDescriptor {
-            digest: self.digest.clone(),
-            size: self.size as i64,
-            media_type: self.media_type.clone(),
-            ..Default::default()
-        }
-    }
-}
-
 /// Image manifest with layers info
 #[derive(Debug, Clone)]
 pub struct ImageManifest {
     pub config_digest: String,
+    pub config_size: u64,
     pub layers: Vec<LayerDescriptor>,
     pub total_size: u64,
 }
 
+impl ImageManifest {
+    pub(crate) fn from_oci(manifest: &OciImageManifest) -> Self {
+        let layers: Vec<LayerDescriptor> = manifest
+            .layers
+            .iter()
+            .map(|l| LayerDescriptor {
+                digest: l.digest.clone(),
+                size: l.size as u64,
+                media_type: l.media_type.clone(),
+            })
+            .collect();
+
+        let total_size = layers.iter().map(|l| l.size).sum();
+
+        Self {
+            config_digest: manifest.config.digest.clone(),
+            config_size: manifest.config.size as u64,
+            layers,
+            total_size,
+        }
+    }
+}
+
+/// Pick the manifest matching the requested platform from an image index
+pub(crate) fn select_platform<'a>(
+    manifests: &'a [ImageIndexEntry],
+    options: &PullOptions,
+) -> Result<&'a ImageIndexEntry> {
+    manifests
+        .iter()
+        .find(|m| {
+            if let Some(p) = &m.platform {
+                p.os == options.platform_os && p.architecture == options.platform_arch
+            } else {
+                false
+            }
+        })
+        .with_context(|| {
+            format!(
+                "Platform {}/{} not found in image index",
+                options.platform_os, options.platform_arch
+            )
+        })
+}
+
+/// First byte of a `Content-Range: bytes <start>-<end>/<size>` value
+fn range_start(content_range: &str) -> Option<u64> {
+    let range = content_range.trim().strip_prefix("bytes ")?;
+    let (start, _) = range.split_once('-')?;
+    start.trim().parse().ok()
+}
+
+/// Key of the repository of `reference` on the registry actually contacted
+fn repository_key(reference: &Reference, mirror: bool) -> String {
+    format!(
+        "{}{}/{}",
+        if mirror { "mirror:" } else { "" },
+        reference.resolve_registry(),
+        reference.repository()
+    )
+}
+
+/// How requests to a repository are authorized
+#[derive(Clone)]
+enum RequestAuth {
+    Anonymous,
+    Basic(String, String),
+    Bearer(String),
+}
+
+impl RequestAuth {
+    fn apply(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match self {
+            RequestAuth::Anonymous => request,
+            RequestAuth::Basic(username, password) => request.basic_auth(username, Some(password)),
+            RequestAuth::Bearer(token) => request.bearer_auth(token),
+        }
+    }
+}
+
+/// Connection settings shared by every request of a [`RegistryClient`]
+#[derive(Debug, Clone, Default)]
+pub struct RegistryConfig {
+    /// Registries (`host:port`) reached over plain HTTP instead of HTTPS
+    pub insecure_registries: Vec<String>,
+    /// PEM files with extra CA certificates to trust (e.g. a private CA)
+    pub ca_certificates: Vec<PathBuf>,
+}
+
+impl RegistryConfig {
+    fn is_insecure(&self, registry: &str) -> bool {
+        self.insecure_registries.iter().any(|r| r == registry)
+    }
+}
+
 /// Client for interacting with OCI registries
 pub struct RegistryClient {
     client: Client,
+    http: reqwest::Client,
     auth: RegistryAuth,
+    config: RegistryConfig,
+    docker_config: Option<DockerConfig>,
+    cache: Option<BlobCache>,
+    /// Authorization of manifest and blob requests, per `registry/repository`
+    authorized: Mutex<HashMap<String, RequestAuth>>,
 }
 
 impl RegistryClient {
-    pub fn new(auth: RegistryAuth) -> Self {
-        let config = ClientConfig {
-            protocol: ClientProtocol::Https,
+    /// Create a client with the given credentials
+    ///
+    /// When `auth` is `Anonymous`, credentials are looked up per registry in
+    /// the Docker config (`$DOCKER_CONFIG/config.json` or `~/.docker/config.json`).
+    /// Fails when the HTTP client cannot be set up (no usable TLS backend).
+    pub fn new(auth: RegistryAuth) -> Result<Self> {
+        Self::with_config(auth, RegistryConfig::default())
+    }
+
+    /// Create a client with custom connection settings
+    pub fn with_config(auth: RegistryAuth, config: RegistryConfig) -> Result<Self> {
+        let protocol = if config.insecure_registries.is_empty() {
+            ClientProtocol::Https
+        } else {
+            ClientProtocol::HttpsExcept(config.insecure_registries.clone())
+        };
+
+        let mut extra_root_certificates = Vec::new();
+        let mut http = reqwest::Client::builder();
+        for path in &config.ca_certificates {
+            let pem = std::fs::read(path)
+                .with_context(|| format!("Failed to read CA certificate {:?}", path))?;
+            let cert = reqwest::Certificate::from_pem(&pem)
+                .with_context(|| format!("Invalid CA certificate {:?}", path))?;
+            http = http.add_root_certificate(cert);
+            extra_root_certificates.push(Certificate {
+                encoding: CertificateEncoding::Pem,
+                data: pem,
+            });
+        }
+
+        let client = Client::new(ClientConfig {
+            protocol,
+            extra_root_certificates,
             ..Default::default()
+        });
+        let http = http.build().context("Failed to build HTTP client")?;
+        let docker_config = DockerConfig::load().unwrap_or_else(|e| {
+            warn!("Ignoring docker config: {:#}", e);
+            None
+        });
+
+        Ok(Self {
+            client,
+            http,
+            auth,
+            config,
+            docker_config,
+            cache: None,
+            authorized: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Base URL of the registry API for `reference`
+    fn base_url(&self, reference: &Reference) -> String {
+        let scheme = if self.config.is_insecure(reference.registry()) {
+            "http"
+        } else {
+            "https"
         };
-        let client = Client::new(config);
-        Self { client, auth }
+        format!("{}://{}", scheme, reference.resolve_registry())
+    }
+
+    /// Use an explicit docker config instead of the one found in the environment
+    pub fn with_docker_config(mut self, config: DockerConfig) -> Self {
+        self.docker_config = Some(config);
+        self
+    }
+
+    /// Look blobs up in `cache` before downloading them, and store downloads there
+    pub fn with_cache(mut self, cache: BlobCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Whether blobs go through a [`BlobCache`]
+    pub fn has_cache(&self) -> bool {
+        self.cache.is_some()
+    }
+
+    /// Credentials to use for the registry of `reference`
+    ///
+    /// Explicit credentials belong to the upstream registry and are never sent to
+    /// a `mirror`, which gets what the Docker config has for it.
+    async fn auth_for(&self, reference: &Reference, mirror: bool) -> Result<RegistryAuth> {
+        match (&self.auth, &self.docker_config) {
+            (auth, _) if !mirror && !matches!(auth, RegistryAuth::Anonymous) => Ok(auth.clone()),
+            (_, Some(config)) => config
+                .credentials_for(reference.registry())
+                .await
+                .with_context(|| {
+                    format!("Failed to resolve credentials for {}", reference.registry())
+                }),
+            (_, None) => Ok(RegistryAuth::Anonymous),
+        }
+    }
+
+    /// Resolve credentials for `reference` into what the OCI client understands
+    async fn oci_auth(&self, reference: &Reference, mirror: bool) -> Result<OciRegistryAuth> {
+        let auth = match self.auth_for(reference, mirror).await? {
+            RegistryAuth::Anonymous => OciRegistryAuth::Anonymous,
+            RegistryAuth::Basic { username, password } => {
+                OciRegistryAuth::Basic(username, password)
+            }
+            RegistryAuth::Bearer { token } => OciRegistryAuth::Bearer(token),
+            RegistryAuth::IdentityToken { token } => {
+                let base_url = self.base_url(reference);
+                let access_token =
+                    exchange_identity_token(&self.http, &base_url, reference.repository(), &token)
+                        .await
+                        .with_context(|| {
+                            format!(
+                                "Failed to exchange identity token for {}",
+                                reference.registry()
+                            )
+                        })?;
+                OciRegistryAuth::Bearer(access_token)
+            }
+        };
+        Ok(auth)
+    }
+
+    /// Authorization for requests to the repository of `reference`
+    ///
+    /// The token exchange is done once per repository, and per candidate since a
+    /// blob may come from another candidate than the manifest did.
+    async fn authenticate(&self, reference: &Reference, mirror: bool) -> Result<RequestAuth> {
+        let key = repository_key(reference, mirror);
+        if let Some(auth) = self.authorized.lock().unwrap().get(&key) {
+            return Ok(auth.clone());
+        }
+
+        let credentials = self.oci_auth(reference, mirror).await?;
+        let token = self
+            .client
+            .auth(reference, &credentials, RegistryOperation::Pull)
+            .await
+            .with_context(|| format!("Failed to authenticate to {}", reference.registry()))?;
+        let auth = match (token, credentials) {
+            (Some(token), _) => RequestAuth::Bearer(token),
+            (None, OciRegistryAuth::Basic(username, password)) => {
+                RequestAuth::Basic(username, password)
+            }
+            (None, _) => RequestAuth::Anonymous,
+        };
+        self.authorized.lock().unwrap().insert(key, auth.clone());
+        Ok(auth)
+    }
+
+    /// Send an authorized request to the repository of `reference`
+    ///
+    /// An expired token is renewed once. Error statuses fail with a [`StatusError`]
+    /// holding the response headers, which tell how long a rate limit lasts.
+    async fn send<F>(
+        &self,
+        reference: &Reference,
+        mirror: bool,
+        url: &str,
+        request: F,
+    ) -> Result<reqwest::Response>
+    where
+        F: Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+    {
+        let mut response = None;
+        for _ in 0..2 {
+            let auth = self.authenticate(reference, mirror).await?;
+            let sent = auth
+                .apply(request(&self.http))
+                .send()
+                .await
+                .with_context(|| format!("Failed to reach {}", url))?;
+            if sent.status() != reqwest::StatusCode::UNAUTHORIZED {
+                response = Some(sent);
+                break;
+            }
+            self.authorized
+                .lock()
+                .unwrap()
+                .remove(&repository_key(reference, mirror));
+            response = Some(sent);
+        }
+
+        let response = response.expect("at least one request is sent");
+        if !response.status().is_success() {
+            return Err(StatusError::from_response(response).await.into());
+        }
+        Ok(response)
     }
 
     pub fn parse_reference(image: &str) -> Result<Reference> {
@@ -96,42 +454,42 @@ impl RegistryClient {
             .with_context(|| format!("Failed to parse image reference: {}", image))
     }
 
-    /// Fetch the manifest for an image
+    /// Fetch the manifest for an image, trying configured mirrors first
     pub async fn fetch_manifest(
         &self,
         reference: &Reference,
         options: &PullOptions,
     ) -> Result<ImageManifest> {
-        info!("Fetching manifest for {}", reference);
+        let mut candidates = options.candidates(reference);
+        let upstream = candidates.pop().expect("upstream is always a candidate");
 
-        let auth: OciRegistryAuth = self.auth.clone().into();
+        for mirror in &candidates {
+            match self.fetch_manifest_from(mirror, true, options).await {
+                Ok(manifest) => return Ok(manifest),
+                Err(e) => warn!("Mirror {} failed, trying next: {:#}", mirror.registry(), e),
+            }
+        }
 
-        let (manifest, _digest) = self
-            .client
-            .pull_manifest(reference, &auth)
-            .await
-            .with_context(|| format!("Failed to pull manifest for {}", reference))?;
+        self.fetch_manifest_from(&upstream, false, options).await
+    }
+
+    async fn fetch_manifest_from(
+        &self,
+        reference: &Reference,
+        mirror: bool,
+        options: &PullOptions,
+    ) -> Result<ImageManifest> {
+        info!("Fetching manifest for {}", reference);
+
+        let manifest = self
+            .pull_verified_manifest(reference, mirror, options)
+            .await?;
 
         let oci_manifest = match manifest {
-            oci_distribution::manifest::OciManifest::Image(m) => m,
-            oci_distribution::manifest::OciManifest::ImageIndex(index) => {
+            OciManifest::Image(m) => m,
+            OciManifest::ImageIndex(index) => {
                 // Multi-arch image, find the right platform
-                let platform_manifest = index
-                    .manifests
-                    .iter()
-                    .find(|m| {
-                        if let Some(p) = &m.platform {
-                            p.os == options.platform_os && p.architecture == options.platform_arch
-                        } else {
-                            false
-                        }
-                    })
-                    .with_context(|| {
-                        format!(
-                            "Platform {}/{} not found in image index",
-                            options.platform_os, options.platform_arch
-                        )
-                    })?;
+                let platform_manifest = select_platform(&index.manifests, options)?;
 
                 debug!("Found platform manifest: {:?}", platform_manifest.digest);
 
@@ -142,85 +500,512 @@ impl RegistryClient {
                     platform_manifest.digest.clone(),
                 );
 
-                let (platform_manifest, _) = self
-                    .client
-                    .pull_manifest(&platform_ref, &auth)
+                let platform_manifest = self
+                    .pull_verified_manifest(&platform_ref, mirror, options)
                     .await
                     .with_context(|| "Failed to pull platform-specific manifest")?;
 
                 match platform_manifest {
-                    oci_distribution::manifest::OciManifest::Image(m) => m,
+                    OciManifest::Image(m) => m,
                     _ => anyhow::bail!("Expected image manifest, got index"),
                 }
             }
         };
 
-        let layers: Vec<LayerDescriptor> = oci_manifest
-            .layers
-            .iter()
-            .map(|l| LayerDescriptor {
-                digest: l.digest.clone(),
-                size: l.size as u64,
-                media_type: l.media_type.clone(),
-            })
-            .collect();
+        Ok(ImageManifest::from_oci(&oci_manifest))
+    }
 
-        let total_size = layers.iter().map(|l| l.size).sum();
+    /// Pull a manifest and check it against the pinned digest of `reference`,
+    /// or the digest reported by the registry for tags
+    async fn pull_verified_manifest(
+        &self,
+        reference: &Reference,
+        mirror: bool,
+        options: &PullOptions,
+    ) -> Result<OciManifest> {
+        let url = format!(
+            "{}/v2/{}/manifests/{}",
+            self.base_url(reference),
+            reference.repository(),
+            reference.digest().or(reference.tag()).unwrap_or("latest")
+        );
+        let accept = MANIFEST_MEDIA_TYPES.join(", ");
 
-        Ok(ImageManifest {
-            config_digest: oci_manifest.config.digest.clone(),
-            layers,
-            total_size,
+        let what = format!("Pulling manifest for {}", reference);
+        let (data, digest) = retry::with_backoff(&options.retry, &what, || async {
+            let pull = async {
+                let response = self
+                    .send(reference, mirror, &url, |http| {
+                        http.get(&url).header(reqwest::header::ACCEPT, &accept)
+                    })
+                    .await?;
+                let digest = response
+                    .headers()
+                    .get("docker-content-digest")
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string);
+                Ok::<_, anyhow::Error>((response.bytes().await?, digest))
+            };
+            pull.await
+                .with_context(|| format!("Failed to pull manifest for {}", reference))
         })
+        .await?;
+
+        if let Some(digest) = reference.digest().or(digest.as_deref()) {
+            verify_blob(digest, &data, None)
+                .with_context(|| format!("Manifest for {} failed verification", reference))?;
+        }
+
+        serde_json::from_slice(&data).with_context(|| format!("Invalid manifest for {}", reference))
     }
 
-    /// Pull a specific layer and return its content as bytes
+    /// Pull a blob and return its content as bytes
+    ///
+    /// Meant for small blobs such as image configs, use [`Self::pull_layer_to_file`]
+    /// for layers. The blob cache is consulted first when configured, then
+    /// mirrors are tried in order before the upstream registry.
     pub async fn pull_layer(
         &self,
         reference: &Reference,
         layer: &LayerDescriptor,
+        options: &PullOptions,
     ) -> Result<Vec<u8>> {
-        debug!("Pulling layer {} ({} bytes)", layer.digest, layer.size);
+        if let Some(path) = self.cached(layer).await? {
+            return tokio::fs::read(&path)
+                .await
+                .with_context(|| format!("Failed to read cached {:?}", path));
+        }
+
+        let data = self.download_bytes(reference, layer, options).await?;
+
+        if let Some(cache) = &self.cache {
+            if let Err(e) = cache.insert(&layer.digest, &data) {
+                warn!("Failed to cache {}: {:#}", layer.digest, e);
+            }
+        }
+        Ok(data)
+    }
+
+    async fn download_bytes(
+        &self,
+        reference: &Reference,
+        layer: &LayerDescriptor,
+        options: &PullOptions,
+    ) -> Result<Vec<u8>> {
+        let mut candidates = options.candidates(reference);
+        let upstream = candidates.pop().expect("upstream is always a candidate");
+
+        for mirror in &candidates {
+            match self.pull_bytes_from(mirror, true, layer, options).await {
+                Ok(data) => return Ok(data),
+                Err(e) => warn!("Mirror {} failed, trying next: {:#}", mirror.registry(), e),
+            }
+        }
+
+        self.pull_bytes_from(&upstream, false, layer, options).await
+    }
+
+    /// Pull a small blob from one registry, starting over after transient failures
+    async fn pull_bytes_from(
+        &self,
+        reference: &Reference,
+        mirror: bool,
+        layer: &LayerDescriptor,
+        options: &PullOptions,
+    ) -> Result<Vec<u8>> {
+        let what = format!("Pulling blob {}", layer.digest);
+        retry::with_backoff(&options.retry, &what, || async {
+            let mut data = Vec::new();
+            self.pull_blob_from(reference, mirror, layer, &mut data)
+                .await?;
+            Ok(data)
+        })
+        .await
+    }
+
+    /// Stream a layer to `dest` without holding it in memory
+    ///
+    /// The blob is checked against the layer digest and size, a mismatch fails with
+    /// [`crate::BuilderError::DigestMismatch`] or [`crate::BuilderError::SizeMismatch`].
+    /// Interrupted transfers are resumed with range requests. With a blob cache,
+    /// `dest` is a copy of the cached blob (downloaded into the cache on a miss),
+    /// so writing to it never touches the cache. The copy is a reflink where the
+    /// filesystem supports it. Prefer [`Self::pull_layer_to_cache`] to read the
+    /// cached blob in place.
+    /// Returns the number of bytes written.
+    pub async fn pull_layer_to_file(
+        &self,
+        reference: &Reference,
+        layer: &LayerDescriptor,
+        options: &PullOptions,
+        dest: &Path,
+    ) -> Result<u64> {
+        if self.cache.is_some() {
+            let cached = self.pull_layer_to_cache(reference, layer, options).await?;
+            // Never write through whatever `dest` is, it may be a link to the cache
+            match tokio::fs::remove_file(dest).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(e).with_context(|| format!("Failed to replace {:?}", dest));
+                }
+                _ => {}
+            }
+            // copy_file_range reflinks on filesystems that can share extents
+            return tokio::fs::copy(&cached, dest)
+                .await
+                .with_context(|| format!("Failed to copy {:?} to {:?}", cached, dest));
+        }
+
+        // Whatever `dest` held is not part of this blob
+        File::create(dest)
+            .await
+            .with_context(|| format!("Failed to create {:?}", dest))?;
+        self.download_to_file(reference, layer, options, dest).await
+    }
+
+    /// Make sure `layer` is in the blob cache and return its path there
+    pub async fn pull_layer_to_cache(
+        &self,
+        reference: &Reference,
+        layer: &LayerDescriptor,
+        options: &PullOptions,
+    ) -> Result<PathBuf> {
+        let cache = self.cache.as_ref().context("No blob cache configured")?;
+        if let Some(path) = self.cached(layer).await? {
+            return Ok(path);
+        }
+
+        // Kept across runs, a build interrupted midway picks up where it stopped
+        let partial = cache.partial_path(&layer.digest)?;
+        // Another task or build may be writing the same partial download
+        let lock = {
+            let (cache, digest) = (cache.clone(), layer.digest.clone());
+            tokio::task::spawn_blocking(move || cache.lock_partial(&digest)).await??
+        };
+        if let Some(path) = self.cached(layer).await? {
+            // Completed while we waited for the lock
+            return Ok(path);
+        }
+
+        self.download_to_file(reference, layer, options, &partial)
+            .await?;
+        let path = cache.commit_partial(&partial, &layer.digest)?;
+        drop(lock);
+        Ok(path)
+    }
+
+    /// Path of `layer` in the blob cache, if it is there
+    async fn cached(&self, layer: &LayerDescriptor) -> Result<Option<PathBuf>> {
+        let Some(cache) = self.cache.clone() else {
+            return Ok(None);
+        };
+        let (digest, size) = (layer.digest.clone(), layer.size);
+        // Verifying hits hashes the layer, which would hold up the runtime
+        Ok(tokio::task::spawn_blocking(move || cache.get(&digest, size)).await?)
+    }
+
+    /// Download `layer` to `dest`, trying mirrors in order before the upstream registry
+    ///
+    /// Bytes already in `dest` are taken as the start of the blob and resumed.
+    async fn download_to_file(
+        &self,
+        reference: &Reference,
+        layer: &LayerDescriptor,
+        options: &PullOptions,
+        dest: &Path,
+    ) -> Result<u64> {
+        let mut candidates = options.candidates(reference);
+        let upstream = candidates.pop().expect("upstream is always a candidate");
+
+        for mirror in &candidates {
+            match self
+                .pull_layer_to_file_from(mirror, true, layer, options, dest)
+                .await
+            {
+                Ok(written) => return Ok(written),
+                Err(e) => warn!("Mirror {} failed, trying next: {:#}", mirror.registry(), e),
+            }
+        }
+
+        self.pull_layer_to_file_from(&upstream, false, layer, options, dest)
+            .await
+    }
+
+    /// Download `layer` from one registry, resuming after transient failures
+    async fn pull_layer_to_file_from(
+        &self,
+        reference: &Reference,
+        mirror: bool,
+        layer: &LayerDescriptor,
+        options: &PullOptions,
+        dest: &Path,
+    ) -> Result<u64> {
+        let what = format!("Pulling layer {}", layer.digest);
+        retry::with_backoff(&options.retry, &what, || {
+            self.resume_layer_download(reference, mirror, layer, dest)
+        })
+        .await
+    }
+
+    fn blob_url(&self, reference: &Reference, digest: &str) -> String {
+        format!(
+            "{}/v2/{}/blobs/{}",
+            self.base_url(reference),
+            reference.repository(),
+            digest
+        )
+    }
+
+    /// Continue downloading `layer` into `dest` from wherever an earlier attempt stopped
+    async fn resume_layer_download(
+        &self,
+        reference: &Reference,
+        mirror: bool,
+        layer: &LayerDescriptor,
+        dest: &Path,
+    ) -> Result<u64> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dest)
+            .await
+            .with_context(|| format!("Failed to open {:?}", dest))?;
+        let mut offset = file.metadata().await?.len();
+        let url = self.blob_url(reference, &layer.digest);
+
+        let response = if offset > 0 && offset < layer.size {
+            debug!("Resuming {} from byte {}", layer.digest, offset);
+            let range = format!("bytes={}-", offset);
+            let response = self
+                .send(reference, mirror, &url, |http| {
+                    http.get(&url).header(reqwest::header::RANGE, &range)
+                })
+                .await
+                .with_context(|| format!("Failed to pull layer {}", layer.digest))?;
+            if response.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+                let content_range = response
+                    .headers()
+                    .get(reqwest::header::CONTENT_RANGE)
+                    .and_then(|v| v.to_str().ok());
+                if content_range.and_then(range_start) != Some(offset) {
+                    return Err(anyhow::Error::new(RangeMismatch {
+                        offset,
+                        content_range: content_range.map(str::to_string),
+                    })
+                    .context(format!("Failed to resume layer {}", layer.digest)));
+                }
+            } else {
+                // The whole blob is coming, start over
+                debug!("{} ignored the range request", reference.registry());
+                offset = 0;
+            }
+            response
+        } else {
+            offset = 0;
+            debug!(
+                "Pulling blob {} ({} bytes) from {}",
+                layer.digest,
+                layer.size,
+                reference.registry()
+            );
+            self.send(reference, mirror, &url, |http| http.get(&url))
+                .await
+                .with_context(|| format!("Failed to pull layer {}", layer.digest))?
+        };
+        file.set_len(offset).await?;
+
+        let mut writer = VerifyingWriter::new(&mut file, &layer.digest, Some(layer.size))?;
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let read = writer.get_mut().read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            writer.resume_with(&buf[..read]);
+        }
+
+        let mut stream = response.bytes_stream();
+        let transfer = async {
+            while let Some(chunk) = stream.try_next().await? {
+                writer.write_all(&chunk).await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+        // Keep what arrived before a failure for the next attempt
+        writer.flush().await?;
+        transfer.with_context(|| format!("Failed to pull layer {}", layer.digest))?;
 
-        let _auth: OciRegistryAuth = self.auth.clone().into();
-        let descriptor = layer.to_oci_descriptor();
+        if let Err(e) = writer.finish() {
+            // Never resume from corrupted data
+            file.set_len(0).await?;
+            return Err(e.context(format!("Layer {} failed verification", layer.digest)));
+        }
 
-        // Create a buffer to receive the blob data
-        let mut data = Vec::with_capacity(layer.size as usize);
+        Ok(file.metadata().await?.len())
+    }
 
-        self.client
-            .pull_blob(reference, &descriptor, &mut data)
+    async fn pull_blob_from<W: AsyncWrite + Unpin + Send>(
+        &self,
+        reference: &Reference,
+        mirror: bool,
+        layer: &LayerDescriptor,
+        out: &mut W,
+    ) -> Result<()> {
+        debug!(
+            "Pulling blob {} ({} bytes) from {}",
+            layer.digest,
+            layer.size,
+            reference.registry()
+        );
+
+        let url = self.blob_url(reference, &layer.digest);
+        let mut writer = VerifyingWriter::new(out, &layer.digest, Some(layer.size))?;
+
+        let transfer = async {
+            let response = self
+                .send(reference, mirror, &url, |http| http.get(&url))
+                .await?;
+            let mut stream = response.bytes_stream();
+            while let Some(chunk) = stream.try_next().await? {
+                writer.write_all(&chunk).await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+        transfer
             .await
             .with_context(|| format!("Failed to pull layer {}", layer.digest))?;
 
-        Ok(data)
+        writer
+            .finish()
+            .with_context(|| format!("Layer {} failed verification", layer.digest))
+    }
+
+    /// Fetch the image config blob referenced by a manifest
+    pub async fn fetch_config(
+        &self,
+        reference: &Reference,
+        manifest: &ImageManifest,
+        options: &PullOptions,
+    ) -> Result<Vec<u8>> {
+        let config = LayerDescriptor {
+            digest: manifest.config_digest.clone(),
+            size: manifest.config_size,
+            media_type: CONFIG_MEDIA_TYPE.to_string(),
+        };
+        self.pull_layer(reference, &config, options).await
     }
 
-    /// Pull all layers and return them in order
+    /// Pull all layers into `dest_dir`, returning their paths in manifest order
+    ///
+    /// With a blob cache, the returned paths point into the cache instead and
+    /// `dest_dir` is left empty.
+    /// Up to `options.max_concurrent_downloads` layers are downloaded at once.
+    /// A layer repeated in the manifest is downloaded once and its path repeated.
+    /// The progress callback receives the number of completed downloads.
     pub async fn pull_all_layers(
         &self,
         reference: &Reference,
         manifest: &ImageManifest,
+        options: &PullOptions,
+        dest_dir: &Path,
         progress_callback: Option<Arc<dyn Fn(usize, usize) + Send + Sync>>,
-    ) -> Result<Vec<Vec<u8>>> {
-        let mut layers_data = Vec::with_capacity(manifest.layers.len());
-        let total = manifest.layers.len();
-
+    ) -> Result<Vec<PathBuf>> {
+        let mut first_index = HashMap::new();
         for (idx, layer) in manifest.layers.iter().enumerate() {
-            if let Some(ref cb) = progress_callback {
-                cb(idx + 1, total);
-            }
-            let data = self.pull_layer(reference, layer).await?;
-            layers_data.push(data);
+            first_index.entry(layer.digest.as_str()).or_insert(idx);
         }
+        let unique: Vec<(usize, &LayerDescriptor)> = manifest
+            .layers
+            .iter()
+            .enumerate()
+            .filter(|(idx, layer)| first_index[layer.digest.as_str()] == *idx)
+            .collect();
+
+        let total = unique.len();
+        let completed = AtomicUsize::new(0);
 
-        Ok(layers_data)
+        let paths: HashMap<usize, PathBuf> = stream::iter(unique)
+            .map(|(idx, layer)| {
+                let path = dest_dir.join(format!("layer-{}", idx));
+                let completed = &completed;
+                let progress_callback = &progress_callback;
+                async move {
+                    let path = if self.cache.is_some() {
+                        self.pull_layer_to_cache(reference, layer, options).await?
+                    } else {
+                        self.pull_layer_to_file(reference, layer, options, &path)
+                            .await?;
+                        path
+                    };
+                    let done = completed.fetch_add(1, Ordering::Relaxed) + 1;
+                    if let Some(cb) = progress_callback {
+                        cb(done, total);
+                    }
+                    Ok::<_, anyhow::Error>((idx, path))
+                }
+            })
+            .buffered(options.max_concurrent_downloads.max(1))
+            .try_collect()
+            .await?;
+
+        Ok(manifest
+            .layers
+            .iter()
+            .map(|layer| paths[&first_index[layer.digest.as_str()]].clone())
+            .collect())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// Answer HTTP requests on `listener` with `handler`, which gets the request
+    /// line and `Authorization` header and returns (status, extra headers, body)
+    fn serve<H>(listener: TcpListener, handler: H)
+    where
+        H: Fn(&str, Option<&str>) -> (u16, String, Vec<u8>) + Send + 'static,
+    {
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (read_half, mut write_half) = stream.into_split();
+                let mut reader = BufReader::new(read_half);
+
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).await.unwrap();
+                let mut authorization = None;
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line).await.unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("authorization") {
+                            authorization = Some(value.trim().to_string());
+                        }
+                    }
+                }
+
+                let (status, headers, body) = handler(&request_line, authorization.as_deref());
+                let head = format!(
+                    "HTTP/1.1 {} X\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    headers,
+                    body.len()
+                );
+                write_half.write_all(head.as_bytes()).await.unwrap();
+                write_half.write_all(&body).await.unwrap();
+                write_half.shutdown().await.unwrap();
+            }
+        });
+    }
 
     #[test]
     fn test_parse_reference_simple() {
@@ -236,4 +1021,363 @@ mod tests {
         assert_eq!(reference.repository(), "user/repo");
         assert_eq!(reference.tag(), Some("v1"));
     }
+
+    #[test]
+    fn test_mirror_candidates() {
+        let options = PullOptions::default()
+            .mirror("docker.io", "mirror.gcr.io")
+            .mirror("docker.io", "localhost:5000");
+
+        let reference = RegistryClient::parse_reference("alpine:3.19").unwrap();
+        let candidates: Vec<String> = options
+            .candidates(&reference)
+            .iter()
+            .map(|r| r.whole())
+            .collect();
+        assert_eq!(
+            candidates,
+            vec![
+                "mirror.gcr.io/library/alpine:3.19",
+                "localhost:5000/library/alpine:3.19",
+                "docker.io/library/alpine:3.19",
+            ]
+        );
+
+        let other = RegistryClient::parse_reference("ghcr.io/user/repo:v1").unwrap();
+        assert_eq!(options.candidates(&other).len(), 1);
+    }
+
+    #[test]
+    fn test_insecure_registry_uses_http() {
+        let config = RegistryConfig {
+            insecure_registries: vec!["localhost:5000".to_string()],
+            ..Default::default()
+        };
+        let client = RegistryClient::with_config(RegistryAuth::Anonymous, config).unwrap();
+
+        let local = RegistryClient::parse_reference("localhost:5000/app:dev").unwrap();
+        assert_eq!(client.base_url(&local), "http://localhost:5000");
+
+        let remote = RegistryClient::parse_reference("ghcr.io/user/repo:v1").unwrap();
+        assert_eq!(client.base_url(&remote), "https://ghcr.io");
+    }
+
+    #[tokio::test]
+    async fn test_blob_fallback_authenticates_upstream() {
+        const BLOB: &[u8] = b"hello";
+        const DIGEST: &str =
+            "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+        let mirror = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mirror_host = mirror.local_addr().unwrap().to_string();
+        let upstream_host = upstream.local_addr().unwrap().to_string();
+
+        serve(mirror, |_, _| (404, String::new(), Vec::new()));
+        let challenge = format!(
+            "WWW-Authenticate: Bearer realm=\"http://{}/token\",service=\"test\"\r\n",
+            upstream_host
+        );
+        serve(upstream, move |request, authorization| {
+            if request.starts_with("GET /token") {
+                (200, String::new(), br#"{"token": "secret"}"#.to_vec())
+            } else if request.starts_with("GET /v2/app/blobs/")
+                && authorization == Some("Bearer secret")
+            {
+                (200, String::new(), BLOB.to_vec())
+            } else {
+                (401, challenge.clone(), Vec::new())
+            }
+        });
+
+        let config = RegistryConfig {
+            insecure_registries: vec![mirror_host.clone(), upstream_host.clone()],
+            ..Default::default()
+        };
+        let client = RegistryClient::with_config(RegistryAuth::Anonymous, config)
+            .unwrap()
+            .with_docker_config(DockerConfig::default());
+        let options = PullOptions::default()
+            .mirror(&upstream_host, &mirror_host)
+            .retry_policy(RetryPolicy::none());
+        let reference =
+            RegistryClient::parse_reference(&format!("{}/app:v1", upstream_host)).unwrap();
+        let layer = LayerDescriptor {
+            digest: DIGEST.to_string(),
+            size: BLOB.len() as u64,
+            media_type: "application/vnd.oci.image.layer.v1.tar".to_string(),
+        };
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let dest = dir.path().join("blob");
+        client
+            .pull_layer_to_file(&reference, &layer, &options, &dest)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), BLOB);
+    }
+
+    #[tokio::test]
+    async fn test_mirrors_never_get_upstream_credentials() {
+        const BLOB: &[u8] = b"hello";
+        const DIGEST: &str =
+            "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        // base64("user:pass")
+        const BASIC: &str = "Basic dXNlcjpwYXNz";
+
+        let mirror = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mirror_host = mirror.local_addr().unwrap().to_string();
+        let upstream_host = upstream.local_addr().unwrap().to_string();
+
+        let mirror_authorizations = Arc::new(Mutex::new(Vec::new()));
+        let seen = mirror_authorizations.clone();
+        let challenge = format!(
+            "WWW-Authenticate: Bearer realm=\"http://{}/token\",service=\"mirror\"\r\n",
+            mirror_host
+        );
+        serve(mirror, move |request, authorization| {
+            seen.lock().unwrap().push(authorization.map(str::to_string));
+            if request.starts_with("GET /token") {
+                (200, String::new(), br#"{"token": "mirror"}"#.to_vec())
+            } else if authorization == Some("Bearer mirror") {
+                (404, String::new(), Vec::new())
+            } else {
+                (401, challenge.clone(), Vec::new())
+            }
+        });
+        serve(upstream, |request, authorization| {
+            if request.starts_with("GET /v2/ ") {
+                (200, String::new(), Vec::new())
+            } else if authorization == Some(BASIC) {
+                (200, String::new(), BLOB.to_vec())
+            } else {
+                (401, String::new(), Vec::new())
+            }
+        });
+
+        let config = RegistryConfig {
+            insecure_registries: vec![mirror_host.clone(), upstream_host.clone()],
+            ..Default::default()
+        };
+        let auth = RegistryAuth::Basic {
+            username: "user".to_string(),
+            password: "pass".to_string(),
+        };
+        let client = RegistryClient::with_config(auth, config)
+            .unwrap()
+            .with_docker_config(DockerConfig::default());
+        let options = PullOptions::default()
+            .mirror(&upstream_host, &mirror_host)
+            .retry_policy(RetryPolicy::none());
+        let reference =
+            RegistryClient::parse_reference(&format!("{}/app:v1", upstream_host)).unwrap();
+        let layer = LayerDescriptor {
+            digest: DIGEST.to_string(),
+            size: BLOB.len() as u64,
+            media_type: "application/vnd.oci.image.layer.v1.tar".to_string(),
+        };
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let dest = dir.path().join("blob");
+        client
+            .pull_layer_to_file(&reference, &layer, &options, &dest)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), BLOB);
+
+        let mirror_authorizations = mirror_authorizations.lock().unwrap();
+        assert!(!mirror_authorizations.is_empty());
+        assert!(mirror_authorizations
+            .iter()
+            .flatten()
+            .all(|authorization| authorization == "Bearer mirror"));
+    }
+
+    #[tokio::test]
+    async fn test_writing_to_dest_leaves_cache_intact() {
+        const BLOB: &[u8] = b"hello";
+        const DIGEST: &str =
+            "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = BlobCache::new(dir.path().join("cache"));
+        let cached = cache.insert(DIGEST, BLOB).unwrap();
+        let client = RegistryClient::new(RegistryAuth::Anonymous)
+            .unwrap()
+            .with_cache(cache);
+        let reference = RegistryClient::parse_reference("localhost:1/app:v1").unwrap();
+        let layer = LayerDescriptor {
+            digest: DIGEST.to_string(),
+            size: BLOB.len() as u64,
+            media_type: "application/vnd.oci.image.layer.v1.tar".to_string(),
+        };
+
+        // Left linked to the cache by an older build
+        let dest = dir.path().join("layer");
+        std::fs::hard_link(&cached, &dest).unwrap();
+
+        client
+            .pull_layer_to_file(&reference, &layer, &PullOptions::default(), &dest)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), BLOB);
+
+        std::fs::write(&dest, b"scribbled over").unwrap();
+        assert_eq!(std::fs::read(&cached).unwrap(), BLOB);
+    }
+
+    const HELLO: &[u8] = b"hello";
+    const HELLO_DIGEST: &str =
+        "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    /// Resume a download of [`HELLO`] that stopped after two bytes from a registry
+    /// answering blob requests with `handler`, returns the number of blob requests
+    async fn resume_hello<H>(dest: &Path, handler: H) -> Result<usize>
+    where
+        H: Fn(usize, &str) -> (u16, String, Vec<u8>) + Send + 'static,
+    {
+        let registry = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = registry.local_addr().unwrap().to_string();
+        let blob_requests = Arc::new(AtomicUsize::new(0));
+        let counter = blob_requests.clone();
+        serve(registry, move |request, _| {
+            if request.contains("/blobs/") {
+                handler(counter.fetch_add(1, Ordering::SeqCst), request)
+            } else {
+                (200, String::new(), Vec::new())
+            }
+        });
+
+        let config = RegistryConfig {
+            insecure_registries: vec![host.clone()],
+            ..Default::default()
+        };
+        let client = RegistryClient::with_config(RegistryAuth::Anonymous, config)
+            .unwrap()
+            .with_docker_config(DockerConfig::default());
+        let reference = RegistryClient::parse_reference(&format!("{}/app:v1", host)).unwrap();
+        let layer = LayerDescriptor {
+            digest: HELLO_DIGEST.to_string(),
+            size: HELLO.len() as u64,
+            media_type: "application/vnd.oci.image.layer.v1.tar".to_string(),
+        };
+        let options = PullOptions {
+            retry: RetryPolicy {
+                max_retries: 2,
+                initial_backoff: std::time::Duration::from_millis(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        std::fs::write(dest, &HELLO[..2]).unwrap();
+        client
+            .pull_layer_to_file_from(&reference, false, &layer, &options, dest)
+            .await?;
+        Ok(blob_requests.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn test_ignored_range_restarts_download() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let dest = dir.path().join("layer");
+
+        let requests = resume_hello(&dest, |_, _| (200, String::new(), HELLO.to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(requests, 1);
+        assert_eq!(std::fs::read(&dest).unwrap(), HELLO);
+    }
+
+    #[tokio::test]
+    async fn test_mismatched_range_is_retried() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let dest = dir.path().join("layer");
+
+        let requests = resume_hello(&dest, |attempt, request| {
+            assert!(request.starts_with("GET "));
+            if attempt == 0 {
+                (
+                    206,
+                    "Content-Range: bytes 0-4/5\r\n".to_string(),
+                    HELLO.to_vec(),
+                )
+            } else {
+                (
+                    206,
+                    "Content-Range: bytes 2-4/5\r\n".to_string(),
+                    HELLO[2..].to_vec(),
+                )
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(requests, 2);
+        assert_eq!(std::fs::read(&dest).unwrap(), HELLO);
+    }
+
+    #[test]
+    fn test_range_start() {
+        assert_eq!(range_start("bytes 2-4/5"), Some(2));
+        assert_eq!(range_start("bytes 0-4/*"), Some(0));
+        assert_eq!(range_start("bytes */5"), None);
+        assert_eq!(range_start("items 2-4/5"), None);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_wait_comes_from_failing_response() {
+        let registry = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = registry.local_addr().unwrap().to_string();
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        serve(registry, move |request, _| {
+            seen.lock().unwrap().push(request.trim().to_string());
+            if request.starts_with("GET /v2/app/manifests/v1") {
+                (429, "Retry-After: 3600\r\n".to_string(), Vec::new())
+            } else {
+                (200, String::new(), Vec::new())
+            }
+        });
+
+        let config = RegistryConfig {
+            insecure_registries: vec![host.clone()],
+            ..Default::default()
+        };
+        let client = RegistryClient::with_config(RegistryAuth::Anonymous, config)
+            .unwrap()
+            .with_docker_config(DockerConfig::default());
+        let reference = RegistryClient::parse_reference(&format!("{}/app:v1", host)).unwrap();
+
+        let err = client
+            .fetch_manifest(&reference, &PullOptions::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<crate::BuilderError>(),
+            Some(crate::BuilderError::RateLimited {
+                retry_after_secs: 3600
+            })
+        ));
+
+        let manifest_requests: Vec<String> = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| r.contains("/manifests/"))
+            .cloned()
+            .collect();
+        assert_eq!(manifest_requests.len(), 1);
+        assert!(manifest_requests[0].starts_with("GET "));
+    }
+
+    #[test]
+    fn test_missing_ca_certificate() {
+        let config = RegistryConfig {
+            ca_certificates: vec![PathBuf::from("/nonexistent/ca.pem")],
+            ..Default::default()
+        };
+        assert!(RegistryClient::with_config(RegistryAuth::Anonymous, config).is_err());
+    }
 }
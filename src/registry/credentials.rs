@@ -0,0 +1,385 @@
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+use super::RegistryAuth;
+
+/// Key used by the Docker CLI for Docker Hub credentials
+const DOCKER_HUB_SERVER: &str = "https://index.docker.io/v1/";
+
+/// How long a credential helper may take before it is killed (it may be
+/// waiting on a keychain prompt nobody will answer)
+const HELPER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Credentials configuration read from a Docker `config.json`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+    #[serde(default, rename = "credsStore")]
+    creds_store: Option<String>,
+    #[serde(default, rename = "credHelpers")]
+    cred_helpers: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct AuthEntry {
+    #[serde(default)]
+    auth: Option<String>,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default, rename = "identitytoken")]
+    identity_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HelperOutput {
+    #[serde(rename = "Username")]
+    username: String,
+    #[serde(rename = "Secret")]
+    secret: String,
+}
+
+impl DockerConfig {
+    /// Load the config from `$DOCKER_CONFIG/config.json` or `~/.docker/config.json`
+    ///
+    /// Returns `None` when no config file exists.
+    pub fn load() -> Result<Option<Self>> {
+        let path = match config_path(std::env::var_os("DOCKER_CONFIG"), std::env::var_os("HOME")) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        if !path.exists() {
+            debug!("No docker config at {:?}", path);
+            return Ok(None);
+        }
+
+        Self::from_path(&path).map(Some)
+    }
+
+    /// Load the config from an explicit path
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path)
+            .with_context(|| format!("Failed to read docker config {:?}", path))?;
+        serde_json::from_slice(&data)
+            .with_context(|| format!("Failed to parse docker config {:?}", path))
+    }
+
+    /// Resolve the credentials to use for a registry host (e.g. `docker.io`, `ghcr.io`)
+    ///
+    /// Lookup order follows the Docker CLI: `credHelpers`, then `credsStore`,
+    /// then the inline `auths` entries.
+    pub async fn credentials_for(&self, registry: &str) -> Result<RegistryAuth> {
+        let host = normalize_registry(registry);
+
+        if let Some(helper) = self
+            .cred_helpers
+            .iter()
+            .find(|(key, _)| normalize_registry(key) == host)
+            .map(|(_, helper)| helper)
+        {
+            return run_credential_helper(helper, &server_url(&host)).await;
+        }
+
+        if let Some(store) = &self.creds_store {
+            match run_credential_helper(store, &server_url(&host)).await {
+                Ok(RegistryAuth::Anonymous) => {}
+                Ok(auth) => return Ok(auth),
+                Err(e) => warn!("Credential store '{}' failed for {}: {:#}", store, host, e),
+            }
+        }
+
+        match self
+            .auths
+            .iter()
+            .find(|(key, _)| normalize_registry(key) == host)
+        {
+            Some((key, entry)) => entry
+                .to_auth()
+                .with_context(|| format!("Invalid auth entry for {} in docker config", key)),
+            None => Ok(RegistryAuth::Anonymous),
+        }
+    }
+}
+
+impl AuthEntry {
+    fn to_auth(&self) -> Result<RegistryAuth> {
+        if let Some(encoded) = self.auth.as_deref().filter(|a| !a.is_empty()) {
+            let decoded = BASE64
+                .decode(encoded.trim())
+                .context("auth field is not valid base64")?;
+            let decoded = String::from_utf8(decoded).context("auth field is not valid UTF-8")?;
+            let (username, password) = decoded
+                .split_once(':')
+                .context("auth field is not in username:password form")?;
+            return Ok(RegistryAuth::Basic {
+                username: username.to_string(),
+                password: password.to_string(),
+            });
+        }
+
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            return Ok(RegistryAuth::Basic {
+                username: username.clone(),
+                password: password.clone(),
+            });
+        }
+
+        if let Some(token) = self.identity_token.as_deref().filter(|t| !t.is_empty()) {
+            return Ok(RegistryAuth::IdentityToken {
+                token: token.to_string(),
+            });
+        }
+
+        Ok(RegistryAuth::Anonymous)
+    }
+}
+
+/// Location of the docker config file given `$DOCKER_CONFIG` and `$HOME`
+fn config_path(docker_config: Option<OsString>, home: Option<OsString>) -> Option<PathBuf> {
+    match docker_config {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("config.json")),
+        _ => home.map(|home| PathBuf::from(home).join(".docker").join("config.json")),
+    }
+}
+
+/// Reduce a registry key (`https://index.docker.io/v1/`, `ghcr.io`) to its host
+fn normalize_registry(key: &str) -> String {
+    let host = key
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+    let host = host.split('/').next().unwrap_or(host);
+
+    match host {
+        "index.docker.io" | "registry-1.docker.io" | "docker.io" => "docker.io".to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// Server URL passed to credential helpers, matching what `docker login` stored
+fn server_url(host: &str) -> String {
+    if host == "docker.io" {
+        DOCKER_HUB_SERVER.to_string()
+    } else {
+        host.to_string()
+    }
+}
+
+/// Run `docker-credential-<helper> get` for a server URL
+async fn run_credential_helper(helper: &str, server: &str) -> Result<RegistryAuth> {
+    let program = format!("docker-credential-{}", helper);
+    debug!("Querying {} for {}", program, server);
+    query_helper(&program, server, HELPER_TIMEOUT).await
+}
+
+/// Ask the credential helper `program` for the credentials of `server`
+async fn query_helper(program: &str, server: &str, timeout: Duration) -> Result<RegistryAuth> {
+    let mut child = Command::new(program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to run credential helper {}", program))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(server.as_bytes()).await?;
+    }
+
+    let output = tokio::time::timeout(timeout, child.wait_with_output())
+        .await
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "Credential helper {} did not answer within {}s",
+                program,
+                timeout.as_secs_f32()
+            )
+        })?
+        .with_context(|| format!("Failed to run credential helper {}", program))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        // Helpers report missing entries with a non-zero exit code
+        if stderr.contains("credentials not found") {
+            return Ok(RegistryAuth::Anonymous);
+        }
+        anyhow::bail!(
+            "{} exited with {}: {}",
+            program,
+            output.status,
+            stderr.trim()
+        );
+    }
+
+    parse_helper_output(&output.stdout)
+}
+
+fn parse_helper_output(stdout: &[u8]) -> Result<RegistryAuth> {
+    let output: HelperOutput =
+        serde_json::from_slice(stdout).context("Invalid credential helper output")?;
+
+    // Helpers return identity tokens with this placeholder username
+    if output.username == "<token>" {
+        return Ok(RegistryAuth::IdentityToken {
+            token: output.secret,
+        });
+    }
+
+    Ok(RegistryAuth::Basic {
+        username: output.username,
+        password: output.secret,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn write_config(dir: &TempDir, content: &str) -> PathBuf {
+        let path = dir.path().join("config.json");
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_config_path() {
+        assert_eq!(
+            config_path(Some("/etc/docker".into()), Some("/home/user".into())),
+            Some(PathBuf::from("/etc/docker/config.json"))
+        );
+        assert_eq!(
+            config_path(None, Some("/home/user".into())),
+            Some(PathBuf::from("/home/user/.docker/config.json"))
+        );
+        assert_eq!(config_path(None, None), None);
+    }
+
+    #[test]
+    fn test_normalize_registry() {
+        assert_eq!(
+            normalize_registry("https://index.docker.io/v1/"),
+            "docker.io"
+        );
+        assert_eq!(normalize_registry("registry-1.docker.io"), "docker.io");
+        assert_eq!(normalize_registry("https://ghcr.io"), "ghcr.io");
+        assert_eq!(normalize_registry("localhost:5000"), "localhost:5000");
+    }
+
+    #[tokio::test]
+    async fn test_base64_auth_entry() {
+        let dir = TempDir::new().unwrap();
+        // "user:secret:with:colons"
+        let path = write_config(
+            &dir,
+            r#"{"auths": {"https://index.docker.io/v1/": {"auth": "dXNlcjpzZWNyZXQ6d2l0aDpjb2xvbnM="}}}"#,
+        );
+
+        let config = DockerConfig::from_path(&path).unwrap();
+        match config.credentials_for("docker.io").await.unwrap() {
+            RegistryAuth::Basic { username, password } => {
+                assert_eq!(username, "user");
+                assert_eq!(password, "secret:with:colons");
+            }
+            other => panic!("unexpected auth {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unknown_registry_is_anonymous() {
+        let dir = TempDir::new().unwrap();
+        let path = write_config(&dir, r#"{"auths": {"ghcr.io": {"auth": "dTpw"}}}"#);
+
+        let config = DockerConfig::from_path(&path).unwrap();
+        assert!(matches!(
+            config.credentials_for("quay.io").await.unwrap(),
+            RegistryAuth::Anonymous
+        ));
+    }
+
+    #[test]
+    fn test_parse_helper_output() {
+        let auth = parse_helper_output(
+            br#"{"ServerURL": "gcr.io", "Username": "oauth2accesstoken", "Secret": "ya29"}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            auth,
+            RegistryAuth::Basic { ref username, ref password }
+                if username == "oauth2accesstoken" && password == "ya29"
+        ));
+
+        let auth = parse_helper_output(br#"{"Username": "<token>", "Secret": "refresh"}"#).unwrap();
+        assert!(matches!(auth, RegistryAuth::IdentityToken { ref token } if token == "refresh"));
+    }
+
+    #[tokio::test]
+    async fn test_identity_token_entry() {
+        let dir = TempDir::new().unwrap();
+        let path = write_config(
+            &dir,
+            r#"{"auths": {"myregistry.azurecr.io": {"auth": "", "identitytoken": "refresh"}}}"#,
+        );
+
+        let config = DockerConfig::from_path(&path).unwrap();
+        assert!(matches!(
+            config.credentials_for("myregistry.azurecr.io").await.unwrap(),
+            RegistryAuth::IdentityToken { ref token } if token == "refresh"
+        ));
+    }
+
+    /// Write an executable credential helper running `script`
+    fn write_helper(dir: &TempDir, script: &str) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = dir.path().join("docker-credential-test");
+        fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn test_query_helper() {
+        let dir = TempDir::new().unwrap();
+        let helper = write_helper(
+            &dir,
+            r#"read server; echo "{\"Username\": \"$server\", \"Secret\": \"s\"}""#,
+        );
+
+        let auth = query_helper(helper.to_str().unwrap(), "ghcr.io", HELPER_TIMEOUT)
+            .await
+            .unwrap();
+        assert!(matches!(
+            auth,
+            RegistryAuth::Basic { ref username, .. } if username == "ghcr.io"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_hanging_helper_times_out() {
+        let dir = TempDir::new().unwrap();
+        let helper = write_helper(&dir, "exec sleep 60");
+
+        let err = query_helper(
+            helper.to_str().unwrap(),
+            "ghcr.io",
+            Duration::from_millis(200),
+        )
+        .await
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("did not answer"));
+    }
+}
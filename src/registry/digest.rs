@@ -0,0 +1,236 @@
+use anyhow::Result;
+use sha2::{Digest, Sha256, Sha512};
+use std::io::{self, Read};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::AsyncWrite;
+
+use crate::error::BuilderError;
+
+enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+/// Incremental check of a blob against the digest (and size) from its descriptor
+pub(crate) struct DigestVerifier {
+    expected: String,
+    expected_size: Option<u64>,
+    hasher: Hasher,
+    size: u64,
+}
+
+impl DigestVerifier {
+    pub fn new(digest: &str, expected_size: Option<u64>) -> Result<Self> {
+        let (algorithm, hex) = digest
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Invalid digest {}", digest))?;
+        let (hasher, hex_len) = match algorithm {
+            "sha256" => (Hasher::Sha256(Sha256::new()), 64),
+            "sha512" => (Hasher::Sha512(Sha512::new()), 128),
+            _ => anyhow::bail!("Unsupported digest algorithm {}", algorithm),
+        };
+        if hex.len() != hex_len || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            anyhow::bail!("Invalid digest {}", digest);
+        }
+
+        Ok(Self {
+            expected: digest.to_string(),
+            expected_size,
+            hasher,
+            size: 0,
+        })
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.size += data.len() as u64;
+        match &mut self.hasher {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha512(h) => h.update(data),
+        }
+    }
+
+    pub fn finish(self) -> std::result::Result<(), BuilderError> {
+        if let Some(expected) = self.expected_size {
+            if expected != self.size {
+                return Err(BuilderError::SizeMismatch {
+                    digest: self.expected,
+                    expected,
+                    actual: self.size,
+                });
+            }
+        }
+
+        let actual = match self.hasher {
+            Hasher::Sha256(h) => format!("sha256:{:x}", h.finalize()),
+            Hasher::Sha512(h) => format!("sha512:{:x}", h.finalize()),
+        };
+        if actual != self.expected {
+            return Err(BuilderError::DigestMismatch {
+                expected: self.expected,
+                actual,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Check an in-memory blob (manifest, config...) against its digest
+pub(crate) fn verify_blob(digest: &str, data: &[u8], expected_size: Option<u64>) -> Result<()> {
+    let mut verifier = DigestVerifier::new(digest, expected_size)?;
+    verifier.update(data);
+    Ok(verifier.finish()?)
+}
+
+/// Writer hashing everything that goes through it
+pub(crate) struct VerifyingWriter<W> {
+    inner: W,
+    verifier: DigestVerifier,
+}
+
+impl<W> VerifyingWriter<W> {
+    pub fn new(inner: W, digest: &str, expected_size: Option<u64>) -> Result<Self> {
+        Ok(Self {
+            inner,
+            verifier: DigestVerifier::new(digest, expected_size)?,
+        })
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Account for bytes an earlier, interrupted attempt already wrote
+    pub fn resume_with(&mut self, data: &[u8]) {
+        self.verifier.update(data);
+    }
+
+    /// Check what was written against the expected digest and size
+    pub fn finish(self) -> Result<()> {
+        Ok(self.verifier.finish()?)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for VerifyingWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.verifier.update(&buf[..written]);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Reader failing with `InvalidData` at end of stream when the blob does not match its digest
+pub(crate) struct VerifyingReader<R> {
+    inner: R,
+    verifier: Option<DigestVerifier>,
+}
+
+impl<R> VerifyingReader<R> {
+    pub fn new(inner: R, digest: &str, expected_size: Option<u64>) -> Result<Self> {
+        Ok(Self {
+            inner,
+            verifier: Some(DigestVerifier::new(digest, expected_size)?),
+        })
+    }
+}
+
+impl<R: Read> Read for VerifyingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read > 0 {
+            if let Some(verifier) = &mut self.verifier {
+                verifier.update(&buf[..read]);
+            }
+        } else if !buf.is_empty() {
+            if let Some(verifier) = self.verifier.take() {
+                verifier
+                    .finish()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO_SHA256: &str =
+        "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn test_verify_blob() {
+        assert!(verify_blob(HELLO_SHA256, b"hello", Some(5)).is_ok());
+
+        let err = verify_blob(HELLO_SHA256, b"hellp", None).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BuilderError>(),
+            Some(BuilderError::DigestMismatch { .. })
+        ));
+
+        let err = verify_blob(HELLO_SHA256, b"hello", Some(6)).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BuilderError>(),
+            Some(BuilderError::SizeMismatch { actual: 5, .. })
+        ));
+    }
+
+    #[test]
+    fn test_verify_sha512() {
+        let digest = "sha512:9b71d224bd62f3785d96d46ad3ea3d73319bfbc2890caadae2dff72519673ca72323c3d99ba5c11d7c7acc6e14b8c5da0c4663475c2e5c3adef46f73bcdec043";
+        assert!(verify_blob(digest, b"hello", None).is_ok());
+        assert!(verify_blob("md5:5d41402abc4b2a76b9719d911017c592", b"hello", None).is_err());
+        assert!(verify_blob("sha256:abc", b"hello", None).is_err());
+    }
+
+    #[test]
+    fn test_verifying_reader_fails_at_eof() {
+        let mut data = Vec::new();
+        VerifyingReader::new(&b"hello"[..], HELLO_SHA256, Some(5))
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, b"hello");
+
+        let err = VerifyingReader::new(&b"corrupt"[..], HELLO_SHA256, None)
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_verifying_writer() {
+        use tokio::io::AsyncWriteExt;
+
+        let mut writer = VerifyingWriter::new(Vec::new(), HELLO_SHA256, Some(5)).unwrap();
+        writer.write_all(b"hello").await.unwrap();
+        assert!(writer.finish().is_ok());
+
+        let mut writer = VerifyingWriter::new(Vec::new(), HELLO_SHA256, None).unwrap();
+        writer.write_all(b"olleh").await.unwrap();
+        assert!(writer.finish().is_err());
+
+        let mut writer = VerifyingWriter::new(b"he".to_vec(), HELLO_SHA256, Some(5)).unwrap();
+        writer.resume_with(b"he");
+        writer.write_all(b"llo").await.unwrap();
+        assert_eq!(writer.get_mut(), b"hello");
+        assert!(writer.finish().is_ok());
+    }
+}
@@ -1,3 +1,15 @@
+mod cache;
 mod client;
+mod credentials;
+mod digest;
+mod retry;
+mod token;
 
-pub use client::{ImageManifest, LayerDescriptor, PullOptions, RegistryAuth, RegistryClient};
+pub use cache::{BlobCache, CacheEntry};
+pub(crate) use client::select_platform;
+pub use client::{
+    ImageManifest, LayerDescriptor, PullOptions, RegistryAuth, RegistryClient, RegistryConfig,
+};
+pub use credentials::DockerConfig;
+pub(crate) use digest::{verify_blob, VerifyingReader};
+pub use retry::RetryPolicy;
@@ -0,0 +1,449 @@
+use anyhow::Result;
+use oci_client::errors::{OciDistributionError, OciErrorCode};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+use crate::error::BuilderError;
+
+/// How registry requests are retried after transient failures
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt, 0 disables retrying
+    pub max_retries: u32,
+    /// Delay after the first failure, doubled after each following one
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Longest wait accepted when a registry asks to slow down, a longer one fails
+    /// the pull right away with [`BuilderError::RateLimited`]
+    pub max_rate_limit_wait: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 4,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            max_rate_limit_wait: Duration::from_secs(120),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Fail on the first error
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Self::default()
+        }
+    }
+
+    pub fn max_retries(mut self, retries: u32) -> Self {
+        self.max_retries = retries;
+        self
+    }
+
+    /// Delay before retrying after the `attempt`-th failure
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(self.max_backoff)
+    }
+}
+
+/// A registry response with an error status, kept with its headers
+#[derive(Debug)]
+pub(crate) struct StatusError {
+    pub url: String,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub message: String,
+}
+
+impl StatusError {
+    pub async fn from_response(response: reqwest::Response) -> Self {
+        let url = response.url().to_string();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let message = response.text().await.unwrap_or_default();
+        Self {
+            url,
+            status,
+            headers,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} returned {}", self.url, self.status)?;
+        if !self.message.trim().is_empty() {
+            write!(f, ": {}", self.message.trim())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// A `206 Partial Content` answer that does not start where the download stopped
+///
+/// Some caches and load-balanced registries get this wrong once in a while, the
+/// request is retried.
+#[derive(Debug)]
+pub(crate) struct RangeMismatch {
+    pub offset: u64,
+    /// `Content-Range` of the response, if it had one
+    pub content_range: Option<String>,
+}
+
+impl fmt::Display for RangeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.content_range {
+            Some(range) => write!(
+                f,
+                "asked for bytes from {}, got Content-Range {:?}",
+                self.offset, range
+            ),
+            None => write!(
+                f,
+                "asked for bytes from {}, got no Content-Range",
+                self.offset
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RangeMismatch {}
+
+/// Run `operation` until it succeeds, fails permanently or runs out of retries
+///
+/// Transient failures (see [`is_transient`]) are retried with exponential backoff.
+/// When the registry rate limits us, the wait it asked for in the failing response
+/// is used instead.
+pub(crate) async fn with_backoff<T, F, Fut>(
+    policy: &RetryPolicy,
+    what: &str,
+    mut operation: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match operation().await {
+            Err(e) if attempt <= policy.max_retries && is_transient(&e) => {
+                let delay = if is_rate_limited(&e) {
+                    match status_error(&e).and_then(|e| rate_limit_delay(&e.headers)) {
+                        Some(wait) if wait > policy.max_rate_limit_wait => {
+                            return Err(e.context(BuilderError::RateLimited {
+                                retry_after_secs: wait.as_secs(),
+                            }));
+                        }
+                        Some(wait) => wait,
+                        None => policy.backoff(attempt),
+                    }
+                } else {
+                    policy.backoff(attempt)
+                };
+
+                warn!(
+                    "{} failed (attempt {}/{}), retrying in {:?}: {:#}",
+                    what,
+                    attempt,
+                    policy.max_retries + 1,
+                    delay,
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn status_error(error: &anyhow::Error) -> Option<&StatusError> {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<StatusError>())
+}
+
+/// Whether `error` is worth retrying: 5xx and 429 responses, dropped connections,
+/// timeouts, partial content starting at the wrong offset
+pub(crate) fn is_transient(error: &anyhow::Error) -> bool {
+    is_rate_limited(error)
+        || error.chain().any(|cause| {
+            if cause.downcast_ref::<RangeMismatch>().is_some() {
+                return true;
+            }
+            if let Some(e) = cause.downcast_ref::<StatusError>() {
+                return is_transient_status(e.status.as_u16());
+            }
+            if let Some(e) = cause.downcast_ref::<OciDistributionError>() {
+                return matches!(
+                    e,
+                    OciDistributionError::ServerError { code, .. } if is_transient_status(*code)
+                );
+            }
+            if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+                return is_transient_request(e);
+            }
+            if let Some(e) = cause.downcast_ref::<io::Error>() {
+                // Body streams report network failures as io errors wrapping the reqwest one
+                if let Some(e) = e.get_ref().and_then(|e| e.downcast_ref::<reqwest::Error>()) {
+                    return is_transient_request(e);
+                }
+                return matches!(
+                    e.kind(),
+                    io::ErrorKind::ConnectionReset
+                        | io::ErrorKind::ConnectionAborted
+                        | io::ErrorKind::BrokenPipe
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::UnexpectedEof
+                        | io::ErrorKind::Interrupted
+                );
+            }
+            false
+        })
+}
+
+/// Whether the registry answered 429 / `TOOMANYREQUESTS`
+fn is_rate_limited(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<StatusError>() {
+            return e.status == StatusCode::TOO_MANY_REQUESTS;
+        }
+        match cause.downcast_ref::<OciDistributionError>() {
+            Some(OciDistributionError::ServerError { code, .. }) => {
+                return *code == StatusCode::TOO_MANY_REQUESTS.as_u16()
+            }
+            Some(OciDistributionError::RegistryError { envelope, .. }) => {
+                return envelope
+                    .errors
+                    .iter()
+                    .any(|e| e.code == OciErrorCode::Toomanyrequests)
+            }
+            _ => {}
+        }
+        cause
+            .downcast_ref::<reqwest::Error>()
+            .and_then(|e| e.status())
+            == Some(StatusCode::TOO_MANY_REQUESTS)
+    })
+}
+
+fn is_transient_request(error: &reqwest::Error) -> bool {
+    error.is_timeout()
+        || error.is_connect()
+        || error.is_body()
+        || error
+            .status()
+            .is_some_and(|status| is_transient_status(status.as_u16()))
+}
+
+fn is_transient_status(code: u16) -> bool {
+    code == 429 || (500..600).contains(&code)
+}
+
+/// How long a registry wants us to wait, from the headers of the response that failed
+///
+/// Understands `Retry-After` (in seconds or as an HTTP date) and `RateLimit-Reset`.
+/// Docker Hub's `RateLimit-Remaining: 0;w=<window>` only gives the length of the quota
+/// window, not when it refills, so it leaves the wait to the backoff.
+pub(crate) fn rate_limit_delay(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    let seconds = |value: &str| value.trim().parse().ok().map(Duration::from_secs);
+
+    if let Some(retry_after) = header(RETRY_AFTER.as_str()) {
+        if let Some(delay) = seconds(retry_after) {
+            return Some(delay);
+        }
+        if let Some(date) = parse_http_date(retry_after) {
+            return Some(date.duration_since(SystemTime::now()).unwrap_or_default());
+        }
+    }
+    header("ratelimit-reset").and_then(seconds)
+}
+
+/// Parse an IMF-fixdate (`Sun, 06 Nov 1994 08:49:37 GMT`), the date format of HTTP headers
+fn parse_http_date(value: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let (_weekday, rest) = value.trim().split_once(", ")?;
+    let parts: Vec<&str> = rest.split(' ').collect();
+    let [day, month, year, time, "GMT"] = parts[..] else {
+        return None;
+    };
+    let day: u64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|m| *m == month)? as u64 + 1;
+    let year: u64 = year.parse().ok()?;
+    let mut clock = time.split(':').map(|v| v.parse::<u64>().ok());
+    let (hour, minute, second) = (clock.next()??, clock.next()??, clock.next()??);
+    if clock.next().is_some() || year < 1970 || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Days since the epoch of a proleptic Gregorian date, years starting in March
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let days = 365 * y + y / 4 - y / 100 + y / 400 + (153 * m + 2) / 5 + day - 1 - 719_468;
+    let secs = days * 86_400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn server_error(code: u16) -> anyhow::Error {
+        anyhow::Error::new(OciDistributionError::ServerError {
+            code,
+            url: "https://registry.example.com/v2/".to_string(),
+            message: String::new(),
+        })
+        .context("Failed to pull layer")
+    }
+
+    fn fast_policy() -> RetryPolicy {
+        RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(&server_error(503)));
+        assert!(is_transient(&server_error(429)));
+        assert!(!is_transient(&server_error(404)));
+        assert!(is_rate_limited(&server_error(429)));
+        assert!(!is_rate_limited(&server_error(503)));
+
+        let reset: Result<()> = Err(io::Error::from(io::ErrorKind::ConnectionReset).into());
+        assert!(is_transient(
+            &reset.context("Failed to pull layer").unwrap_err()
+        ));
+        assert!(!is_transient(
+            &io::Error::from(io::ErrorKind::NotFound).into()
+        ));
+        assert!(!is_transient(&anyhow::anyhow!("Digest mismatch")));
+    }
+
+    #[test]
+    fn test_backoff_grows_and_is_capped() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff(1), Duration::from_millis(500));
+        assert_eq!(policy.backoff(2), Duration::from_secs(1));
+        assert_eq!(policy.backoff(4), Duration::from_secs(4));
+        assert_eq!(policy.backoff(20), policy.max_backoff);
+    }
+
+    #[test]
+    fn test_rate_limit_delay() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(rate_limit_delay(&headers), None);
+
+        // Docker Hub only tells how long the quota window is
+        headers.insert("ratelimit-remaining", "0;w=21600".parse().unwrap());
+        assert_eq!(rate_limit_delay(&headers), None);
+
+        headers.insert("ratelimit-reset", "45".parse().unwrap());
+        assert_eq!(rate_limit_delay(&headers), Some(Duration::from_secs(45)));
+
+        headers.insert(RETRY_AFTER, "30".parse().unwrap());
+        assert_eq!(rate_limit_delay(&headers), Some(Duration::from_secs(30)));
+
+        headers.insert(
+            RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(rate_limit_delay(&headers), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_parse_http_date() {
+        assert_eq!(
+            parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"),
+            Some(UNIX_EPOCH)
+        );
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(UNIX_EPOCH + Duration::from_secs(784_111_777))
+        );
+        assert_eq!(
+            parse_http_date("Tue, 29 Feb 2028 12:00:00 GMT"),
+            Some(UNIX_EPOCH + Duration::from_secs(1_835_438_400))
+        );
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("soon"), None);
+    }
+
+    #[tokio::test]
+    async fn test_transient_errors_are_retried() {
+        let calls = AtomicU32::new(0);
+        let result = with_backoff(&fast_policy(), "Pulling blob", || async {
+            match calls.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(server_error(502)),
+                _ => Ok("blob"),
+            }
+        })
+        .await;
+
+        assert_eq!(result.unwrap(), "blob");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_permanent_errors_are_not_retried() {
+        let calls = AtomicU32::new(0);
+        let result: Result<()> = with_backoff(&fast_policy(), "Pulling blob", || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(server_error(404))
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_long_rate_limit_fails_fast() {
+        let calls = AtomicU32::new(0);
+        let result: Result<()> = with_backoff(&fast_policy(), "Pulling manifest", || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            let mut headers = HeaderMap::new();
+            headers.insert(RETRY_AFTER, "21600".parse().unwrap());
+            Err(anyhow::Error::new(StatusError {
+                url: "https://registry.example.com/v2/app/manifests/v1".to_string(),
+                status: StatusCode::TOO_MANY_REQUESTS,
+                headers,
+                message: String::new(),
+            }))
+        })
+        .await;
+
+        let err = result.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BuilderError>(),
+            Some(BuilderError::RateLimited {
+                retry_after_secs: 21600
+            })
+        ));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,144 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use tracing::debug;
+
+/// Client id sent to token endpoints, as recommended by the distribution spec
+const CLIENT_ID: &str = "initramfs-builder";
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    #[serde(default)]
+    access_token: Option<String>,
+    #[serde(default)]
+    token: Option<String>,
+}
+
+/// A parsed `WWW-Authenticate: Bearer ...` challenge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BearerChallenge {
+    pub realm: String,
+    pub service: Option<String>,
+}
+
+/// Exchange an identity (refresh) token for a pull-scoped access token
+///
+/// This is the OAuth2 `refresh_token` grant used by `docker login` for
+/// registries that hand out identity tokens (ACR, Harbor, ...).
+pub(crate) async fn exchange_identity_token(
+    http: &reqwest::Client,
+    base_url: &str,
+    repository: &str,
+    refresh_token: &str,
+) -> Result<String> {
+    let challenge = fetch_challenge(http, base_url).await?;
+    debug!("Exchanging identity token at {}", challenge.realm);
+
+    let scope = format!("repository:{}:pull", repository);
+    let mut form = vec![
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token),
+        ("client_id", CLIENT_ID),
+        ("scope", scope.as_str()),
+    ];
+    if let Some(service) = &challenge.service {
+        form.push(("service", service.as_str()));
+    }
+
+    request_token(http.post(&challenge.realm).form(&form), &challenge.realm).await
+}
+
+async fn request_token(request: reqwest::RequestBuilder, realm: &str) -> Result<String> {
+    let response = request
+        .send()
+        .await
+        .with_context(|| format!("Token request to {} failed", realm))?;
+
+    let status = response.status();
+    let body = response.bytes().await?;
+    if !status.is_success() {
+        anyhow::bail!(
+            "Token endpoint {} returned {}: {}",
+            realm,
+            status,
+            String::from_utf8_lossy(&body)
+        );
+    }
+
+    parse_token_response(&body)
+}
+
+/// Probe `/v2/` and read the bearer challenge from the 401 response
+async fn fetch_challenge(http: &reqwest::Client, base_url: &str) -> Result<BearerChallenge> {
+    let url = format!("{}/v2/", base_url);
+    let response = http
+        .get(&url)
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach {}", url))?;
+
+    let header = response
+        .headers()
+        .get(reqwest::header::WWW_AUTHENTICATE)
+        .and_then(|v| v.to_str().ok())
+        .with_context(|| format!("{} did not return an authentication challenge", url))?;
+
+    parse_bearer_challenge(header)
+        .with_context(|| format!("Unsupported authentication challenge: {}", header))
+}
+
+fn parse_bearer_challenge(header: &str) -> Option<BearerChallenge> {
+    let params = header.trim().strip_prefix("Bearer ")?;
+    let mut values = HashMap::new();
+
+    // key="value" pairs, values may contain commas (e.g. scopes)
+    let mut rest = params.trim();
+    while !rest.is_empty() {
+        let (key, after_key) = rest.split_once('=')?;
+        let after_key = after_key.strip_prefix('"')?;
+        let (value, after_value) = after_key.split_once('"')?;
+        values.insert(key.trim().to_lowercase(), value.to_string());
+        rest = after_value.trim_start_matches(',').trim();
+    }
+
+    Some(BearerChallenge {
+        realm: values.remove("realm")?,
+        service: values.remove("service"),
+    })
+}
+
+fn parse_token_response(body: &[u8]) -> Result<String> {
+    let response: TokenResponse =
+        serde_json::from_slice(body).context("Invalid token endpoint response")?;
+    response
+        .access_token
+        .or(response.token)
+        .context("Token endpoint response did not contain a token")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bearer_challenge() {
+        let challenge = parse_bearer_challenge(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/alpine:pull,push""#,
+        )
+        .unwrap();
+        assert_eq!(challenge.realm, "https://auth.docker.io/token");
+        assert_eq!(challenge.service.as_deref(), Some("registry.docker.io"));
+
+        assert!(parse_bearer_challenge(r#"Basic realm="registry""#).is_none());
+    }
+
+    #[test]
+    fn test_parse_token_response() {
+        assert_eq!(
+            parse_token_response(br#"{"access_token": "abc", "expires_in": 300}"#).unwrap(),
+            "abc"
+        );
+        assert_eq!(parse_token_response(br#"{"token": "xyz"}"#).unwrap(), "xyz");
+        assert!(parse_token_response(br#"{}"#).is_err());
+    }
+}
@@ -1,8 +1,20 @@
+use initramfs_builder::registry::{ImageManifest, LayerDescriptor, PullOptions};
 use initramfs_builder::{Compression, InitramfsBuilder};
 use std::io::Read;
 use std::path::PathBuf;
 use tokio::fs;
 
+// Builder for the test image. Set TEST_IMAGE (and TEST_INSECURE_REGISTRY for a
+// local `registry:2`, e.g. localhost:5000) to run without Docker Hub.
+fn test_builder() -> InitramfsBuilder {
+    let image = std::env::var("TEST_IMAGE").unwrap_or_else(|_| "debian:stable-slim".to_string());
+    let mut builder = InitramfsBuilder::new().image(&image);
+    if let Ok(registry) = std::env::var("TEST_INSECURE_REGISTRY") {
+        builder = builder.insecure_registry(&registry);
+    }
+    builder
+}
+
 // Create a basic init script for testing
 async fn create_test_init_script(dir: &std::path::Path) -> PathBuf {
     let init_path = dir.join("init.sh");
@@ -88,8 +100,7 @@ async fn test_build_produces_valid_cpio() -> anyhow::Result<()> {
     let tmp = tempfile::tempdir()?;
     let output = tmp.path().join("output.cpio.gz");
 
-    let result = InitramfsBuilder::new()
-        .image("debian:stable-slim")
+    let result = test_builder()
         .compression(Compression::Gzip)
         .build(&output)
         .await?;
@@ -123,8 +134,7 @@ async fn test_build_result_metadata() -> anyhow::Result<()> {
     let init_script = create_test_init_script(tmp.path()).await;
     let inject_file = create_test_binary(tmp.path(), "my-tool").await;
 
-    let result = InitramfsBuilder::new()
-        .image("debian:stable-slim")
+    let result = test_builder()
         .compression(Compression::Gzip)
         .inject(&inject_file, "/usr/bin/my-tool")
         .init_script(&init_script)
@@ -153,8 +163,7 @@ async fn test_init_script_injection() -> anyhow::Result<()> {
     let output = tmp.path().join("output.cpio.gz");
     let init_script = create_test_init_script(tmp.path()).await;
 
-    let result = InitramfsBuilder::new()
-        .image("debian:stable-slim")
+    let result = test_builder()
         .compression(Compression::Gzip)
         .init_script(&init_script)
         .build(&output)
@@ -188,8 +197,7 @@ async fn test_file_injection() -> anyhow::Result<()> {
     let output = tmp.path().join("output.cpio.gz");
     let inject_file = create_test_binary(tmp.path(), "custom-tool").await;
 
-    let result = InitramfsBuilder::new()
-        .image("debian:stable-slim")
+    let result = test_builder()
         .compression(Compression::Gzip)
         .inject(&inject_file, "/usr/bin/custom-tool")
         .build(&output)
@@ -233,8 +241,7 @@ async fn test_compression_modes() -> anyhow::Result<()> {
     for (label, compression, filename) in &modes {
         let output = tmp.path().join(filename);
 
-        let result = InitramfsBuilder::new()
-            .image("debian:stable-slim")
+        let result = test_builder()
             .compression(*compression)
             .build(&output)
             .await?;
@@ -260,15 +267,13 @@ async fn test_exclude_patterns() -> anyhow::Result<()> {
     let tmp = tempfile::tempdir()?;
 
     let output_full = tmp.path().join("full.cpio.gz");
-    let result_full = InitramfsBuilder::new()
-        .image("debian:stable-slim")
+    let result_full = test_builder()
         .compression(Compression::Gzip)
         .build(&output_full)
         .await?;
 
     let output_excluded = tmp.path().join("excluded.cpio.gz");
-    let result_excluded = InitramfsBuilder::new()
-        .image("debian:stable-slim")
+    let result_excluded = test_builder()
         .compression(Compression::Gzip)
         .exclude(&["usr/share/doc/*", "usr/share/man/*", "var/cache/*"])
         .build(&output_excluded)
@@ -290,15 +295,13 @@ async fn test_reproducibility() -> anyhow::Result<()> {
     let tmp = tempfile::tempdir()?;
 
     let output1 = tmp.path().join("build1.cpio.gz");
-    let result1 = InitramfsBuilder::new()
-        .image("debian:stable-slim")
+    let result1 = test_builder()
         .compression(Compression::Gzip)
         .build(&output1)
         .await?;
 
     let output2 = tmp.path().join("build2.cpio.gz");
-    let result2 = InitramfsBuilder::new()
-        .image("debian:stable-slim")
+    let result2 = test_builder()
         .compression(Compression::Gzip)
         .build(&output2)
         .await?;
@@ -320,3 +323,394 @@ async fn test_reproducibility() -> anyhow::Result<()> {
     );
     Ok(())
 }
+
+// Test 8: Build from a prepared directory
+#[tokio::test]
+async fn test_build_from_directory() -> anyhow::Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let rootfs = tmp.path().join("rootfs");
+    std::fs::create_dir_all(rootfs.join("etc"))?;
+    std::fs::write(rootfs.join("etc/hostname"), "microvm\n")?;
+    let inject_file = create_test_binary(tmp.path(), "agent").await;
+    let output = tmp.path().join("output.cpio.gz");
+
+    let result = InitramfsBuilder::from_directory(&rootfs)
+        .compression(Compression::Gzip)
+        .inject(&inject_file, "/usr/bin/agent")
+        .build(&output)
+        .await?;
+
+    assert_eq!(result.injected_files, 1);
+
+    let compressed = std::fs::read(&output)?;
+    let raw_cpio = decompress_gzip(&compressed);
+    let entries = parse_cpio_entries(&raw_cpio);
+    let paths: Vec<&str> = entries.iter().map(|(p, _, _)| p.as_str()).collect();
+    for expected in ["etc/hostname", "init", "usr", "usr/bin", "usr/bin/agent"] {
+        assert!(
+            paths.contains(&expected),
+            "CPIO should contain {}",
+            expected
+        );
+    }
+
+    // The source directory is left as it was
+    assert!(!rootfs.join("init").exists());
+    assert!(!rootfs.join("usr").exists());
+
+    // Excludes only apply to image layers
+    let excluded = InitramfsBuilder::from_directory(&rootfs)
+        .exclude(&["/etc/*"])
+        .build(tmp.path().join("excluded.cpio"))
+        .await;
+    assert!(excluded.is_err());
+    assert!(!tmp.path().join("excluded.cpio").exists());
+
+    Ok(())
+}
+
+// Test 9: Ownership overrides
+#[tokio::test]
+async fn test_owner_overrides() -> anyhow::Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let rootfs = tmp.path().join("rootfs");
+    std::fs::create_dir_all(rootfs.join("var/lib/agent"))?;
+    std::fs::write(rootfs.join("var/lib/agent/state"), "{}")?;
+    std::fs::write(rootfs.join("hostname"), "microvm\n")?;
+    let output = tmp.path().join("output.cpio");
+
+    InitramfsBuilder::from_directory(&rootfs)
+        .compression(Compression::None)
+        .owner_override(0, 0)
+        .path_owner("/var/lib/agent", 1000, 1000)
+        .build(&output)
+        .await?;
+
+    let raw_cpio = std::fs::read(&output)?;
+    let owner = |path: &str| {
+        let name = format!("{}\0", path);
+        let start = raw_cpio
+            .windows(name.len())
+            .position(|w| w == name.as_bytes())
+            .unwrap_or_else(|| panic!("CPIO should contain {}", path))
+            - 110;
+        let field = |at: usize| {
+            let hex = std::str::from_utf8(&raw_cpio[start + at..start + at + 8]).unwrap();
+            u32::from_str_radix(hex, 16).unwrap()
+        };
+        (field(22), field(30))
+    };
+
+    assert_eq!(owner("hostname"), (0, 0));
+    assert_eq!(owner("init"), (0, 0));
+    assert_eq!(owner("var/lib/agent"), (1000, 1000));
+    assert_eq!(owner("var/lib/agent/state"), (1000, 1000));
+
+    Ok(())
+}
+
+// Test 10: Early device nodes without privileges
+#[tokio::test]
+async fn test_console_and_null_devices() -> anyhow::Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let rootfs = tmp.path().join("rootfs");
+    std::fs::create_dir_all(rootfs.join("etc"))?;
+    let output = tmp.path().join("output.cpio.gz");
+
+    InitramfsBuilder::from_directory(&rootfs)
+        .compression(Compression::Gzip)
+        .build(&output)
+        .await?;
+
+    let raw_cpio = decompress_gzip(&std::fs::read(&output)?);
+    let entries = parse_cpio_entries(&raw_cpio);
+    for device in ["dev/console", "dev/null"] {
+        let (_, mode, size) = entries
+            .iter()
+            .find(|(path, _, _)| path == device)
+            .unwrap_or_else(|| panic!("CPIO should contain {}", device));
+        assert_eq!(
+            mode & 0o170000,
+            0o020000,
+            "{} should be a char device",
+            device
+        );
+        assert_eq!(*size, 0);
+    }
+
+    Ok(())
+}
+
+// Test 11: Archives assembled programmatically
+#[test]
+fn test_programmatic_archive() -> anyhow::Result<()> {
+    use initramfs_builder::{CpioArchive, EntryAttributes, SpecialFile};
+
+    let mut archive = CpioArchive::new();
+    archive.add_directory("/etc", EntryAttributes::new(0o755));
+    archive.add_file(
+        "/etc/hostname",
+        "microvm\n",
+        EntryAttributes::new(0o644).mtime(0),
+    );
+    archive.add_symlink("/bin", "usr/bin", EntryAttributes::new(0o777));
+    archive.add_special(
+        "/dev/console",
+        SpecialFile::CharDevice { major: 5, minor: 1 },
+        EntryAttributes::new(0o600),
+    );
+
+    let mut raw_cpio = Vec::new();
+    archive.write_to(&mut raw_cpio)?;
+    let entries = parse_cpio_entries(&raw_cpio);
+
+    let expected = [
+        ("etc", 0o040755, 0),
+        ("etc/hostname", 0o100644, 8),
+        ("bin", 0o120777, 7),
+        ("dev", 0o040755, 0),
+        ("dev/console", 0o020600, 0),
+    ];
+    let found: Vec<(&str, u32, usize)> = entries
+        .iter()
+        .map(|(path, mode, size)| (path.as_str(), *mode, *size))
+        .collect();
+    assert_eq!(found, expected);
+
+    Ok(())
+}
+
+// Test 12: Byte-for-byte reproducible output
+#[tokio::test]
+async fn test_reproducible_output_is_identical() -> anyhow::Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let rootfs = tmp.path().join("rootfs");
+    std::fs::create_dir_all(rootfs.join("etc"))?;
+    std::fs::write(rootfs.join("etc/hostname"), "microvm\n")?;
+    std::fs::write(rootfs.join("etc/motd"), "hello\n")?;
+    let agent = create_test_binary(tmp.path(), "agent").await;
+
+    let build = |name: &'static str| {
+        let output = tmp.path().join(name);
+        let rootfs = rootfs.clone();
+        let agent = agent.clone();
+        async move {
+            InitramfsBuilder::from_directory(&rootfs)
+                .compression(Compression::Gzip)
+                .inject(&agent, "/usr/bin/agent")
+                .source_date_epoch(1_700_000_000)
+                .build(&output)
+                .await?;
+            anyhow::Ok(std::fs::read(&output)?)
+        }
+    };
+
+    let first = build("first.cpio.gz").await?;
+
+    // Newer host timestamps are clamped to the epoch
+    let later = std::time::SystemTime::now() + std::time::Duration::from_secs(3600);
+    std::fs::File::options()
+        .write(true)
+        .open(rootfs.join("etc/motd"))?
+        .set_modified(later)?;
+
+    let second = build("second.cpio.gz").await?;
+    assert_eq!(first, second, "Reproducible builds should be identical");
+
+    let entries = parse_cpio_entries(&decompress_gzip(&first));
+    let paths: Vec<&str> = entries.iter().map(|(p, _, _)| p.as_str()).collect();
+    let mut sorted = paths.clone();
+    sorted.sort();
+    assert_eq!(paths, sorted, "Entries should be sorted by path");
+
+    Ok(())
+}
+
+/// Single-layer image kept in memory
+struct LayerSource {
+    layer: Vec<u8>,
+}
+
+#[async_trait::async_trait]
+impl initramfs_builder::ImageSource for LayerSource {
+    async fn resolve_manifest(&self, _options: &PullOptions) -> anyhow::Result<ImageManifest> {
+        Ok(ImageManifest {
+            config_digest: "sha256:config".to_string(),
+            config_size: 2,
+            layers: vec![LayerDescriptor {
+                digest: "sha256:layer".to_string(),
+                size: self.layer.len() as u64,
+                media_type: "application/vnd.oci.image.layer.v1.tar".to_string(),
+            }],
+            total_size: self.layer.len() as u64,
+        })
+    }
+
+    async fn open_layer(
+        &self,
+        _layer: &LayerDescriptor,
+        _options: &PullOptions,
+    ) -> anyhow::Result<initramfs_builder::image::LayerReader> {
+        Ok(Box::new(std::io::Cursor::new(self.layer.clone())))
+    }
+
+    async fn fetch_config(
+        &self,
+        _manifest: &ImageManifest,
+        _options: &PullOptions,
+    ) -> anyhow::Result<Vec<u8>> {
+        Ok(b"{}".to_vec())
+    }
+}
+
+/// Layer with a file capability on `app`, plus `extra` files
+fn layer_with_capability(extra: &[&str]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    builder
+        .append_pax_extensions([(
+            "SCHILY.xattr.security.capability",
+            &[0x01, 0x00, 0x00, 0x02, 0x00, 0x20, 0x00, 0x00][..],
+        )])
+        .unwrap();
+    for path in std::iter::once(&"app").chain(extra) {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(0);
+        header.set_mode(0o755);
+        header.set_cksum();
+        builder.append_data(&mut header, path, &b""[..]).unwrap();
+    }
+    builder.into_inner().unwrap()
+}
+
+// Test 13: Extended attributes are only restored by a /init the image can run
+#[tokio::test]
+async fn test_xattrs_without_shell() -> anyhow::Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let output = tmp.path().join("output.cpio");
+    let init = create_test_binary(tmp.path(), "init").await;
+
+    // Distroless-like: no shell to run a generated /init
+    let result = InitramfsBuilder::new()
+        .source(LayerSource {
+            layer: layer_with_capability(&[]),
+        })
+        .compression(Compression::None)
+        .init_script(&init)
+        .build(&output)
+        .await?;
+
+    assert_eq!(result.restored_xattrs, 0);
+    assert_eq!(
+        result.dropped_xattrs,
+        vec![(
+            PathBuf::from("/app"),
+            vec!["security.capability".to_string()]
+        )]
+    );
+    let entries = parse_cpio_entries(&std::fs::read(&output)?);
+    assert!(entries.iter().all(|(path, _, _)| path != ".init"));
+    let (_, _, init_size) = entries.iter().find(|(path, _, _)| path == "init").unwrap();
+    assert_eq!(*init_size, std::fs::metadata(&init)?.len() as usize);
+
+    // With a shell and setfattr, /init restores them first
+    let result = InitramfsBuilder::new()
+        .source(LayerSource {
+            layer: layer_with_capability(&["bin/sh", "usr/bin/setfattr"]),
+        })
+        .compression(Compression::None)
+        .init_script(&init)
+        .build(&output)
+        .await?;
+
+    assert_eq!(result.restored_xattrs, 1);
+    assert!(result.dropped_xattrs.is_empty());
+    let entries = parse_cpio_entries(&std::fs::read(&output)?);
+    assert!(entries.iter().any(|(path, _, _)| path == ".init"));
+
+    Ok(())
+}
+
+// Test 14: The wrapped init never overwrites a file, and injected files drop the image's xattrs
+#[tokio::test]
+async fn test_xattrs_wrapped_init_and_injection() -> anyhow::Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let output = tmp.path().join("output.cpio");
+    let init = create_test_binary(tmp.path(), "init").await;
+
+    // The image already has a /.init (empty, like every file of this layer)
+    let result = InitramfsBuilder::new()
+        .source(LayerSource {
+            layer: layer_with_capability(&["bin/sh", "usr/bin/setfattr", ".init"]),
+        })
+        .compression(Compression::None)
+        .init_script(&init)
+        .build(&output)
+        .await?;
+
+    assert_eq!(result.restored_xattrs, 1);
+    let entries = parse_cpio_entries(&std::fs::read(&output)?);
+    let size_of = |name: &str| {
+        entries
+            .iter()
+            .find(|(path, _, _)| path == name)
+            .map(|(_, _, size)| *size)
+    };
+    assert_eq!(size_of(".init"), Some(0));
+    assert_eq!(
+        size_of(".init.1"),
+        Some(std::fs::metadata(&init)?.len() as usize)
+    );
+
+    // Replacing the file with the capability leaves nothing to restore
+    let agent = create_test_binary(tmp.path(), "agent").await;
+    let result = InitramfsBuilder::new()
+        .source(LayerSource {
+            layer: layer_with_capability(&["bin/sh", "usr/bin/setfattr"]),
+        })
+        .compression(Compression::None)
+        .init_script(&init)
+        .inject(&agent, "/app")
+        .build(&output)
+        .await?;
+
+    assert_eq!(result.restored_xattrs, 0);
+    let entries = parse_cpio_entries(&std::fs::read(&output)?);
+    assert!(entries.iter().all(|(path, _, _)| path != ".init"));
+
+    // Also when the file is replaced through a symlinked directory (opt -> usr)
+    let mut layer = tar::Builder::new(Vec::new());
+    for path in ["bin/sh", "usr/bin/setfattr"] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(0);
+        header.set_mode(0o755);
+        header.set_cksum();
+        layer.append_data(&mut header, path, &b""[..])?;
+    }
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_size(0);
+    header.set_mode(0o777);
+    layer.append_link(&mut header, "opt", "usr")?;
+    layer.append_pax_extensions([(
+        "SCHILY.xattr.security.capability",
+        &[0x01, 0x00, 0x00, 0x02, 0x00, 0x20, 0x00, 0x00][..],
+    )])?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(0);
+    header.set_mode(0o755);
+    header.set_cksum();
+    layer.append_data(&mut header, "usr/app", &b""[..])?;
+
+    let result = InitramfsBuilder::new()
+        .source(LayerSource {
+            layer: layer.into_inner()?,
+        })
+        .compression(Compression::None)
+        .init_script(&init)
+        .inject(&agent, "/opt/app")
+        .build(&output)
+        .await?;
+    assert_eq!(result.restored_xattrs, 0);
+
+    Ok(())
+}